@@ -0,0 +1,82 @@
+// Benchmarks for the hot paths of escaping and unescaping JSON strings.
+//
+// jless is a binary crate, so the modules under test are included directly.
+// Their unit tests aren't run from here.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/escapedwidth.rs"]
+mod escapedwidth;
+#[allow(dead_code)]
+#[path = "../src/jsonstringunescaper.rs"]
+mod jsonstringunescaper;
+
+use jsonstringunescaper::{
+    escape_json_string, find_range_from_escaped, unescape_json_string, UnescapeConfig,
+};
+
+// Unescaped inputs, each roughly 64KiB, so that the different kinds of input
+// are comparable.
+fn inputs() -> Vec<(&'static str, String)> {
+    const LEN: usize = 64 * 1024;
+
+    let repeat = |unit: &str| unit.repeat(LEN / unit.len());
+    vec![
+        ("ascii", repeat("The quick brown fox jumps over the lazy dog. ")),
+        ("escaped", repeat("\"quoted\"\t\\path\\\n\u{1b}[0m")),
+        ("non_ascii", repeat("Ünïcödé 中文テキスト ")),
+        ("surrogates", repeat("😀𐐷🎉")),
+    ]
+}
+
+fn bench_escape(c: &mut Criterion) {
+    let mut group = c.benchmark_group("escape_json_string");
+    for (name, input) in inputs() {
+        group.bench_with_input(BenchmarkId::from_parameter(name), &input, |b, input| {
+            b.iter(|| escape_json_string(black_box(input)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_unescape(c: &mut Criterion) {
+    let config = UnescapeConfig::default();
+    let mut group = c.benchmark_group("unescape_json_string");
+    for (name, input) in inputs() {
+        let escaped = escape_json_string(&input);
+        group.bench_with_input(BenchmarkId::from_parameter(name), &escaped, |b, escaped| {
+            b.iter(|| unescape_json_string(black_box(escaped), &config).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_find_range_from_escaped(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find_range_from_escaped");
+    for (name, input) in inputs() {
+        let escaped_len = escape_json_string(&input).len();
+        let range = escaped_len - 100..escaped_len - 50;
+        group.bench_with_input(BenchmarkId::from_parameter(name), &input, |b, input| {
+            b.iter(|| find_range_from_escaped(black_box(input), range.clone()))
+        });
+    }
+
+    // Regression input: mapping every match of a search back to the
+    // unescaped string rescans the string from the start each time, which is
+    // quadratic in the length of the string.
+    let input = "ab😀\n".repeat(1024);
+    let escaped = escape_json_string(&input);
+    let matches: Vec<_> = escaped.match_indices('a').map(|(start, _)| start..start + 1).collect();
+    group.bench_function("every_match", |b| {
+        b.iter(|| {
+            for range in matches.iter() {
+                black_box(find_range_from_escaped(&input, range.clone()));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_escape, bench_unescape, bench_find_range_from_escaped);
+criterion_main!(benches);
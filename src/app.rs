@@ -663,8 +663,10 @@ impl App {
             SearchDirection::Reverse => "?",
         };
 
-        let search_term = escape_unicode_for_regex(
-            &self.readline(prompt_str, "search input")?);
+        // We search the original JSON text, where non-ASCII characters may
+        // appear as literal "\uXXXX" escapes.
+        let search_input = self.readline(prompt_str, "search input")?;
+        let search_term = escape_unicode_for_regex(&search_input, true).into_owned();
 
         // In vim, /<CR> or ?<CR> is a longcut for repeating the previous search.
         if search_term.is_empty() {
@@ -0,0 +1,485 @@
+use std::ops::Range;
+
+use unicode_width::UnicodeWidthChar;
+
+use crate::jsonstringunescaper::{
+    decode_escaped_char, escaped_chunks, is_bidi_control, is_control, EscapeStyle,
+};
+
+// This module measures escaped strings (e.g., the output of
+// escape_json_string or safe_unescape_json_string) in terminal columns.
+//
+// An escaped string is treated as a sequence of escape-aware chunks (see
+// jsonstringunescaper::escaped_chunk_len). An escape sequence is printed
+// literally, so it occupies one column per byte, while any other char
+// occupies its Unicode display width. Byte offsets and columns that land
+// in the middle of a chunk are rounded down to the start of that chunk,
+// so we never point into the middle of an escape or a wide char.
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct WidthConfig {
+    // Treat characters with East Asian Ambiguous width as two columns
+    // wide, as terminals in CJK locales do.
+    pub cjk: bool,
+}
+
+// Returns the number of columns a single escape-aware chunk (see
+// jsonstringunescaper::escaped_chunk_len) occupies: its length for an escape
+// sequence, or the display width of its char otherwise.
+pub fn chunk_display_width(chunk: &str, width_config: WidthConfig) -> usize {
+    if chunk.starts_with('\\') {
+        return chunk.len();
+    }
+
+    let ch = match chunk.chars().next() {
+        Some(ch) => ch,
+        None => return 0,
+    };
+    let width = if width_config.cjk {
+        ch.width_cjk()
+    } else {
+        ch.width()
+    };
+    // Control characters don't have a width; they don't move the cursor.
+    width.unwrap_or(0)
+}
+
+// Returns the display column at which the chunk containing [byte] starts,
+// i.e., the total width of all the chunks that end at or before [byte].
+pub fn column_of_byte(escaped: &str, byte: usize, width_config: WidthConfig) -> usize {
+    escaped_chunks(escaped)
+        .take_while(|chunk| chunk.end <= byte)
+        .map(|chunk| chunk_display_width(&escaped[chunk], width_config))
+        .sum()
+}
+
+// Returns the byte offset of the chunk boundary at or before display column
+// [col]. If [col] lands in the middle of a wide char or an escape sequence,
+// this returns the start of that chunk. Zero-width chunks (e.g., combining
+// marks) following column [col] are included, so they stay attached to the
+// preceding char. Columns past the end return the length of the string.
+#[allow(dead_code)]
+pub fn byte_of_column(escaped: &str, col: usize, width_config: WidthConfig) -> usize {
+    let mut current_col = 0;
+    for chunk in escaped_chunks(escaped) {
+        current_col += chunk_display_width(&escaped[chunk.clone()], width_config);
+        if current_col > col {
+            return chunk.start;
+        }
+    }
+    escaped.len()
+}
+
+// Splits [escaped] at the chunk boundary nearest display column [col], so
+// that neither half contains part of an escape sequence or char. If [col]
+// lands in the middle of a chunk, the split is made before the chunk when
+// that's at least as close as after it. As with byte_of_column, zero-width
+// chunks stay in the first half with their preceding char.
+#[allow(dead_code)]
+pub fn split_at_width(escaped: &str, col: usize, width_config: WidthConfig) -> (&str, &str) {
+    let mut current_col = 0;
+    for chunk in escaped_chunks(escaped) {
+        let start_col = current_col;
+        current_col += chunk_display_width(&escaped[chunk.clone()], width_config);
+        if current_col > col {
+            let split = if col - start_col <= current_col - col {
+                chunk.start
+            } else {
+                chunk.end
+            };
+            return escaped.split_at(split);
+        }
+    }
+    (escaped, "")
+}
+
+// Returns the number of rows [escaped] occupies when wrapped at [width]
+// columns, including a partially filled final row. An empty string still
+// occupies one row.
+//
+// Chunks are wrapped as a unit, so an escape sequence only gets split across
+// rows if it's wider than an entire row. Note that "\n" escapes are printed
+// literally, and so don't start a new row.
+#[allow(dead_code)]
+pub fn row_count(escaped: &str, width: usize, width_config: WidthConfig) -> usize {
+    let width = width.max(1);
+    let mut rows = 1;
+    let mut col = 0;
+    for chunk in escaped_chunks(escaped) {
+        let chunk_width = chunk_display_width(&escaped[chunk], width_config);
+        if col > 0 && col + chunk_width > width {
+            rows += 1;
+            col = 0;
+        }
+
+        if chunk_width > width {
+            let extra_rows = (chunk_width - 1) / width;
+            rows += extra_rows;
+            col = chunk_width - extra_rows * width;
+        } else {
+            col += chunk_width;
+        }
+    }
+    rows
+}
+
+// Renders [escaped] as a single line summary at most [max_cols] columns
+// wide, e.g., for a compact preview of a long value. Escaped printable chars
+// are shown as the chars themselves, while newlines, tabs and other control
+// characters (escaped or not) are collapsed into the single-column
+// placeholders '↵', '→' and '·'. If the result is still too wide, its middle
+// is replaced with '…', keeping the start and end of the value.
+#[allow(dead_code)]
+pub fn summarize(escaped: &str, max_cols: usize, width_config: WidthConfig) -> String {
+    let pieces: Vec<(char, usize)> = escaped_chunks(escaped)
+        .map(|chunk| {
+            let ch = match decode_escaped_char(&escaped[chunk], EscapeStyle::Json) {
+                Some(('\n', _)) | Some(('\r', _)) => '↵',
+                Some(('\t', _)) => '→',
+                Some((ch, _)) if !is_control(ch) && !is_bidi_control(ch) => ch,
+                _ => '·',
+            };
+            (ch, chunk_display_width(ch.encode_utf8(&mut [0; 4]), width_config))
+        })
+        .collect();
+
+    let total_width: usize = pieces.iter().map(|&(_, width)| width).sum();
+    if total_width <= max_cols {
+        return pieces.iter().map(|&(ch, _)| ch).collect();
+    }
+    if max_cols == 0 {
+        return String::new();
+    }
+
+    // Split the columns left after the ellipsis between the start and the
+    // end, favoring the start.
+    let budget = max_cols - 1;
+    let mut tail_budget = budget / 2;
+    let mut head_budget = budget - tail_budget;
+
+    let mut head_len = 0;
+    for &(_, width) in pieces.iter() {
+        if width > head_budget {
+            break;
+        }
+        head_budget -= width;
+        head_len += 1;
+    }
+    // Give the tail any columns the head couldn't use, e.g., because a wide
+    // char didn't fit.
+    tail_budget += head_budget;
+    let mut tail_len = 0;
+    for &(_, width) in pieces[head_len..].iter().rev() {
+        if width > tail_budget {
+            break;
+        }
+        tail_budget -= width;
+        tail_len += 1;
+    }
+
+    let mut summary: String = pieces[..head_len].iter().map(|&(ch, _)| ch).collect();
+    summary.push('…');
+    summary.extend(pieces[pieces.len() - tail_len..].iter().map(|&(ch, _)| ch));
+    summary
+}
+
+// Truncates [escaped] to at most [max_cols] columns around [match_range]
+// (a byte range in [escaped], e.g., a search hit), so that the match stays
+// in view, as close to the center as possible. Each end that's cut off is
+// replaced with '…'. Returns the truncated string, and the byte range of the
+// match within it. The match is widened to whole chunks, and if it's too
+// wide to fit by itself, only its start is kept.
+#[allow(dead_code)]
+pub fn truncate_around_match(
+    escaped: &str,
+    match_range: Range<usize>,
+    max_cols: usize,
+    width_config: WidthConfig,
+) -> (String, Range<usize>) {
+    let chunks: Vec<(Range<usize>, usize)> = escaped_chunks(escaped)
+        .map(|chunk| {
+            let width = chunk_display_width(&escaped[chunk.clone()], width_config);
+            (chunk, width)
+        })
+        .collect();
+    let n = chunks.len();
+
+    // The window of chunks to show is [start, end), starting with the chunks
+    // of the match.
+    let mut start = chunks
+        .iter()
+        .position(|(chunk, _)| chunk.end > match_range.start)
+        .unwrap_or(n);
+    let mut end = chunks
+        .iter()
+        .rposition(|(chunk, _)| chunk.start < match_range.end)
+        .map_or(start, |last| (last + 1).max(start));
+    let fits = |start: usize, end: usize| {
+        let width: usize = chunks[start..end].iter().map(|&(_, width)| width).sum();
+        width + (start > 0) as usize + (end < n) as usize <= max_cols
+    };
+
+    let match_is_empty = start == end;
+    while end > start && !fits(start, end) {
+        end -= 1;
+    }
+    if !fits(start, end) || (start == end && !match_is_empty) {
+        // Not even the start of the match fits.
+        let truncated = if max_cols == 0 { "" } else { "…" };
+        return (truncated.to_owned(), 0..0);
+    }
+
+    // Grow whichever side has gained fewer columns so far, to center the
+    // match, until neither side can grow any more.
+    let (mut left_cols, mut right_cols) = (0, 0);
+    loop {
+        let can_grow_left = start > 0 && fits(start - 1, end);
+        let can_grow_right = end < n && fits(start, end + 1);
+        if can_grow_left && (left_cols <= right_cols || !can_grow_right) {
+            start -= 1;
+            left_cols += chunks[start].1;
+        } else if can_grow_right {
+            right_cols += chunks[end].1;
+            end += 1;
+        } else {
+            break;
+        }
+    }
+
+    let window = if start < end {
+        chunks[start].0.start..chunks[end - 1].0.end
+    } else {
+        0..0
+    };
+    let mut truncated = String::with_capacity(window.len() + 6);
+    if start > 0 {
+        truncated.push('…');
+    }
+    let offset = truncated.len();
+    truncated.push_str(&escaped[window.clone()]);
+    if end < n {
+        truncated.push('…');
+    }
+
+    let match_start = match_range.start.clamp(window.start, window.end);
+    let match_end = match_range.end.clamp(match_start, window.end);
+    // Widen the match to the chunks it overlaps.
+    let match_start = chunks[start..end]
+        .iter()
+        .find(|(chunk, _)| chunk.end > match_start)
+        .map_or(window.end, |(chunk, _)| chunk.start);
+    let match_end = chunks[start..end]
+        .iter()
+        .find(|(chunk, _)| chunk.start >= match_end)
+        .map_or(window.end, |(chunk, _)| chunk.start)
+        .max(match_start);
+    let shift = |byte: usize| byte - window.start + offset;
+    (truncated, shift(match_start)..shift(match_end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_display_width() {
+        let config = WidthConfig::default();
+        assert_eq!(6, chunk_display_width("\\u00e9", config));
+        assert_eq!(12, chunk_display_width("\\ud83d\\ude00", config));
+        assert_eq!(2, chunk_display_width("\\n", config));
+        assert_eq!(1, chunk_display_width("a", config));
+        assert_eq!(2, chunk_display_width("中", config));
+        assert_eq!(0, chunk_display_width("\u{301}", config));
+        assert_eq!(0, chunk_display_width("", config));
+
+        assert_eq!(1, chunk_display_width("±", config));
+        assert_eq!(2, chunk_display_width("±", WidthConfig { cjk: true }));
+    }
+
+    #[test]
+    fn test_column_of_byte() {
+        let config = WidthConfig::default();
+
+        let escaped = "ab\\u0001cd";
+        assert_eq!(0, column_of_byte(escaped, 0, config));
+        assert_eq!(2, column_of_byte(escaped, 2, config));
+        // Inside the escape rounds to the start of the escape.
+        assert_eq!(2, column_of_byte(escaped, 3, config));
+        assert_eq!(2, column_of_byte(escaped, 7, config));
+        assert_eq!(8, column_of_byte(escaped, 8, config));
+        assert_eq!(10, column_of_byte(escaped, 10, config));
+        assert_eq!(10, column_of_byte(escaped, 100, config));
+
+        let escaped = "中x\\n";
+        // Inside the wide char.
+        assert_eq!(0, column_of_byte(escaped, 1, config));
+        assert_eq!(2, column_of_byte(escaped, 3, config));
+        assert_eq!(3, column_of_byte(escaped, 4, config));
+        assert_eq!(5, column_of_byte(escaped, 6, config));
+
+        // Surrogate pairs are a single chunk.
+        let escaped = "\\uD801\\uDC37z";
+        assert_eq!(0, column_of_byte(escaped, 6, config));
+        assert_eq!(12, column_of_byte(escaped, 12, config));
+    }
+
+    #[test]
+    fn test_column_of_byte_cjk() {
+        // U+00B1 (±) has ambiguous width.
+        let escaped = "±a";
+        assert_eq!(1, column_of_byte(escaped, 2, WidthConfig::default()));
+        assert_eq!(2, column_of_byte(escaped, 2, WidthConfig { cjk: true }));
+    }
+
+    #[test]
+    fn test_byte_of_column() {
+        let config = WidthConfig::default();
+
+        let escaped = "ab\\u0001cd";
+        assert_eq!(0, byte_of_column(escaped, 0, config));
+        assert_eq!(2, byte_of_column(escaped, 2, config));
+        // Inside the escape rounds to the start of the escape.
+        assert_eq!(2, byte_of_column(escaped, 3, config));
+        assert_eq!(2, byte_of_column(escaped, 7, config));
+        assert_eq!(8, byte_of_column(escaped, 8, config));
+        assert_eq!(10, byte_of_column(escaped, 10, config));
+        assert_eq!(10, byte_of_column(escaped, 100, config));
+
+        let escaped = "x中y";
+        assert_eq!(1, byte_of_column(escaped, 1, config));
+        // Inside the wide char.
+        assert_eq!(1, byte_of_column(escaped, 2, config));
+        assert_eq!(4, byte_of_column(escaped, 3, config));
+
+        // Combining marks stay with their base char.
+        let escaped = "e\u{301}f";
+        assert_eq!(0, byte_of_column(escaped, 0, config));
+        assert_eq!(3, byte_of_column(escaped, 1, config));
+    }
+
+    #[test]
+    fn test_byte_of_column_inverts_column_of_byte() {
+        let config = WidthConfig::default();
+        let escaped = "a中\\u0001\\uD801\\uDC37\\nb";
+
+        for chunk in escaped_chunks(escaped) {
+            let col = column_of_byte(escaped, chunk.start, config);
+            assert_eq!(chunk.start, byte_of_column(escaped, col, config));
+        }
+    }
+
+    #[test]
+    fn test_split_at_width() {
+        let config = WidthConfig::default();
+
+        assert_eq!(("ab", "cd"), split_at_width("abcd", 2, config));
+        assert_eq!(("", "abcd"), split_at_width("abcd", 0, config));
+        assert_eq!(("abcd", ""), split_at_width("abcd", 10, config));
+
+        // A wide char straddling the column goes in the second half when
+        // the tie is broken.
+        assert_eq!(("a", "中b"), split_at_width("a中b", 2, config));
+        assert_eq!(("a中", "b"), split_at_width("a中b", 3, config));
+
+        // Escapes go to whichever side is nearer.
+        let escaped = "ab\\u00e9cd";
+        assert_eq!(("ab", "\\u00e9cd"), split_at_width(escaped, 2, config));
+        assert_eq!(("ab", "\\u00e9cd"), split_at_width(escaped, 5, config));
+        assert_eq!(("ab\\u00e9", "cd"), split_at_width(escaped, 6, config));
+        assert_eq!(("ab\\u00e9", "cd"), split_at_width(escaped, 8, config));
+        assert_eq!(
+            ("\\uD801\\uDC37", "z"),
+            split_at_width("\\uD801\\uDC37z", 7, config)
+        );
+
+        // Combining marks stay with their base char.
+        assert_eq!(("e\u{301}", "f"), split_at_width("e\u{301}f", 1, config));
+    }
+
+    #[test]
+    fn test_summarize() {
+        let config = WidthConfig::default();
+
+        assert_eq!("", summarize("", 10, config));
+        assert_eq!("short", summarize("short", 10, config));
+        assert_eq!(
+            "caf\u{e9} \"\u{1f600}\"",
+            summarize("caf\\u00e9 \\\"\\ud83d\\ude00\\\"", 10, config)
+        );
+
+        // Newlines, tabs and controls become single chars.
+        let multi_line = "line 1\\nline\\t2\\r\\n\\u001b[0mend\u{7}";
+        assert_eq!("line 1↵line→2↵↵·[0mend·", summarize(multi_line, 30, config));
+        assert_eq!("line 1↵l…·[0mend·", summarize(multi_line, 17, config));
+        assert_eq!("line…end·", summarize(multi_line, 9, config));
+        assert_eq!("l…", summarize(multi_line, 2, config));
+        assert_eq!("…", summarize(multi_line, 1, config));
+        assert_eq!("", summarize(multi_line, 0, config));
+
+        // Wide chars that don't fit leave their columns to the tail.
+        assert_eq!("中…文", summarize("中文中文", 5, config));
+        assert_eq!("中…文b", summarize("中文中文b", 6, config));
+        for max_cols in 0..10 {
+            let summary = summarize("中a文\\n中b文", max_cols, config);
+            assert!(summary.chars().map(|c| c.width().unwrap()).sum::<usize>() <= max_cols);
+        }
+    }
+
+    #[test]
+    fn test_truncate_around_match() {
+        let config = WidthConfig::default();
+        #[track_caller]
+        fn check(escaped: &str, needle: &str, max_cols: usize, expected: &str) {
+            let start = escaped.find(needle).unwrap();
+            let match_range = start..start + needle.len();
+            let (truncated, range) =
+                truncate_around_match(escaped, match_range, max_cols, WidthConfig::default());
+            assert_eq!(expected, truncated);
+            assert_eq!(needle, &truncated[range]);
+        }
+
+        let escaped = "0123456789abcdefghijMATCHklmnopqrstuvwxyz";
+        // Near the start, middle and end.
+        check(escaped, "012", 11, "0123456789…");
+        check(escaped, "MATCH", 11, "…ijMATCHkl…");
+        check(escaped, "MATCH", 12, "…hijMATCHkl…");
+        check(escaped, "xyz", 11, "…qrstuvwxyz");
+        // Short enough already.
+        check(escaped, "MATCH", 50, escaped);
+
+        // Escapes and wide chars aren't split.
+        check("\\u00e9 long prefix 中MATCH中 long suffix", "MATCH", 11, "…中MATCH中…");
+        check("a long prefix \\u00e9MATCH\\n long suffix", "MATCH", 15, "…\\u00e9MATCH\\n…");
+        check("a long prefix \\u00e9MATCH\\n long suffix", "MATCH", 14, "… \\u00e9MATCH…");
+
+        // A match that's too wide keeps its start.
+        let (truncated, range) = truncate_around_match(escaped, 2..40, 11, config);
+        assert_eq!("…23456789a…", truncated);
+        assert_eq!("23456789a", &truncated[range]);
+        assert_eq!(("…".to_owned(), 0..0), truncate_around_match(escaped, 10..15, 2, config));
+        assert_eq!((String::new(), 0..0), truncate_around_match(escaped, 10..15, 0, config));
+    }
+
+    #[test]
+    fn test_row_count() {
+        let config = WidthConfig::default();
+
+        assert_eq!(1, row_count("", 10, config));
+        assert_eq!(1, row_count("abcd", 4, config));
+        assert_eq!(2, row_count("abcde", 4, config));
+        assert_eq!(3, row_count("abcdefghij", 4, config));
+        assert_eq!(26, row_count(&"a".repeat(101), 4, config));
+
+        // Escapes move to the next row as a unit...
+        assert_eq!(2, row_count("abc\\nd", 4, config));
+        assert_eq!(3, row_count("abc\\ndef", 4, config));
+        assert_eq!(2, row_count("ab\\u00e9", 6, config));
+        // ...unless they're wider than a row.
+        assert_eq!(3, row_count("ab\\u00e9cd", 5, config));
+        assert_eq!(3, row_count("\\ud83d\\ude00", 4, config));
+
+        // So do wide chars.
+        assert_eq!(2, row_count("abc中", 4, config));
+        assert_eq!(1, row_count("ab中", 4, config));
+    }
+}
@@ -404,7 +404,7 @@ impl FlatJson {
             match &row.value {
                 Value::Null | Value::EmptyObject | Value::EmptyArray => buf.push_str("()"),
                 Value::Boolean | Value::Number => buf.push_str(&self.1[row.range.clone()]),
-                Value::String => self.write_sexp_atom(&mut buf, row.range.clone())?,
+                Value::String { .. } => self.write_sexp_atom(&mut buf, row.range.clone())?,
                 Value::OpenContainer { .. } => buf.push('('),
                 Value::CloseContainer { .. } => buf.push(')'),
             }
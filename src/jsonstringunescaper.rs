@@ -1,49 +1,427 @@
+use std::borrow::Cow;
 use std::fmt;
 use std::fmt::Write;
+use std::io;
 use std::ops::Range;
+use std::str::CharIndices;
+
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use unicode_width::UnicodeWidthStr;
+
+use crate::escapedwidth::{column_of_byte, WidthConfig};
 
 #[derive(Debug)]
 pub struct UnescapeError {
     index: usize,
-    codepoint_chars: [u8; 4],
-    error: UnicodeError,
+    // The byte range of the offending escape sequence in the escaped string.
+    span: Range<usize>,
+    error: UnescapeErrorKind,
 }
 
 #[derive(Debug)]
-enum UnicodeError {
-    UnexpectedLowSurrogate,
-    UnmatchedHighSurrogate,
+enum UnescapeErrorKind {
+    UnexpectedLowSurrogate { codepoint_chars: [u8; 4] },
+    UnmatchedHighSurrogate { codepoint_chars: [u8; 4] },
+    // A '\' followed by a character that doesn't form a known escape.
+    InvalidEscape(char),
+    // A '\' at the very end of the input, with nothing to escape.
+    TruncatedEscape,
+    // An escape that should be followed by hex digits, but isn't. Holds the
+    // escape up to and including the first invalid char.
+    InvalidHexEscape(String),
+    // A byte outside of ASCII, in input that must be entirely ASCII.
+    #[allow(dead_code)]
+    NonAsciiByte(u8),
+    // A raw control character, which RFC 8259 requires to be escaped.
+    UnescapedControl(char),
+    // A non-ASCII char, escaped or not, where only ASCII is allowed.
+    #[allow(dead_code)]
+    NonAsciiNotAllowed { ch: char },
+    // A JSON string literal without its opening or closing double quote.
+    #[allow(dead_code)]
+    MissingQuote { position: QuotePosition },
+    // An input longer than the configured maximum, in bytes.
+    InputTooLong { len: usize, limit: usize },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum QuotePosition {
+    #[allow(dead_code)]
+    Start,
+    #[allow(dead_code)]
+    End,
+}
+
+impl UnescapeError {
+    // The value of the "\uXXXX" escape that caused the error, if the error
+    // was caused by one.
+    #[allow(dead_code)]
+    pub fn codepoint(&self) -> Option<u16> {
+        u16::from_str_radix(self.escape_text()?, 16).ok()
+    }
+
+    // The four hex chars of the "\uXXXX" escape that caused the error, as
+    // they were written, if the error was caused by one.
+    #[allow(dead_code)]
+    pub fn escape_text(&self) -> Option<&str> {
+        // The chars are always ASCII hex digits.
+        self.codepoint_chars().map(|chars| std::str::from_utf8(chars).unwrap())
+    }
+
+    // The char that caused the error, if it was caused by a valid char
+    // (i.e., not a surrogate).
+    #[allow(dead_code)]
+    pub fn as_char(&self) -> Option<char> {
+        self.codepoint().and_then(|codepoint| char::from_u32(codepoint as u32))
+    }
+
+    // The byte range of the escape sequence that caused the error, in the
+    // escaped string.
+    #[allow(dead_code)]
+    pub fn byte_range(&self) -> Range<usize> {
+        self.span.clone()
+    }
+
+    // The display column at which the offending escape starts, when the
+    // escaped string [source] it came from is printed as is. Unlike [index],
+    // this accounts for wide chars, and escapes taking up multiple columns.
+    #[allow(dead_code)]
+    pub fn display_column(&self, source: &str, width_config: WidthConfig) -> usize {
+        column_of_byte(source, self.span.start, width_config)
+    }
+
+    // Displays the error along with the escaped string [source] it came from.
+    // The alternate format ("{:#}") also prints [source] on a second line,
+    // and a line of carets pointing at the offending escape under it.
+    #[allow(dead_code)]
+    pub fn display_with_source<'a>(&'a self, source: &'a str) -> UnescapeErrorWithSource<'a> {
+        UnescapeErrorWithSource { err: self, source }
+    }
+
+    fn codepoint_chars(&self) -> Option<&[u8; 4]> {
+        match &self.error {
+            UnescapeErrorKind::UnexpectedLowSurrogate { codepoint_chars }
+            | UnescapeErrorKind::UnmatchedHighSurrogate { codepoint_chars } => {
+                Some(codepoint_chars)
+            }
+            UnescapeErrorKind::InvalidEscape(_)
+            | UnescapeErrorKind::TruncatedEscape
+            | UnescapeErrorKind::InvalidHexEscape(_)
+            | UnescapeErrorKind::NonAsciiByte(_)
+            | UnescapeErrorKind::UnescapedControl(_)
+            | UnescapeErrorKind::NonAsciiNotAllowed { .. }
+            | UnescapeErrorKind::MissingQuote { .. }
+            | UnescapeErrorKind::InputTooLong { .. } => None,
+        }
+    }
 }
 
 impl fmt::Display for UnescapeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "unescaping error at char {}: ", self.index)?;
-        let codepoint_chars = std::str::from_utf8(&self.codepoint_chars).unwrap();
         match &self.error {
-            UnicodeError::UnexpectedLowSurrogate => {
+            UnescapeErrorKind::UnexpectedLowSurrogate { codepoint_chars } => {
+                let codepoint_chars = std::str::from_utf8(codepoint_chars).unwrap();
                 write!(f, "unexpected low surrogate \"\\u{codepoint_chars}\"")
             }
-            UnicodeError::UnmatchedHighSurrogate => write!(
-                f,
-                "high surrogate \"\\u{codepoint_chars}\" not followed by low surrogate"
-            ),
+            UnescapeErrorKind::UnmatchedHighSurrogate { codepoint_chars } => {
+                let codepoint_chars = std::str::from_utf8(codepoint_chars).unwrap();
+                write!(
+                    f,
+                    "high surrogate \"\\u{codepoint_chars}\" not followed by low surrogate"
+                )
+            }
+            UnescapeErrorKind::InvalidEscape(ch) => {
+                write!(f, "invalid escape sequence \"\\{ch}\"")
+            }
+            UnescapeErrorKind::TruncatedEscape => {
+                write!(f, "incomplete escape sequence at end of input")
+            }
+            UnescapeErrorKind::InvalidHexEscape(escape) => {
+                write!(f, "invalid hex digits in escape sequence \"{escape}\"")
+            }
+            UnescapeErrorKind::NonAsciiByte(byte) => {
+                write!(f, "non-ASCII byte 0x{byte:02X} in ASCII input")
+            }
+            UnescapeErrorKind::UnescapedControl(ch) => {
+                write!(f, "unescaped control character U+{:04X}", *ch as u32)
+            }
+            UnescapeErrorKind::NonAsciiNotAllowed { ch } => {
+                write!(f, "non-ASCII char '{ch}' (U+{:04X}) not allowed", *ch as u32)
+            }
+            UnescapeErrorKind::MissingQuote { position: QuotePosition::Start } => {
+                write!(f, "missing opening quote")
+            }
+            UnescapeErrorKind::MissingQuote { position: QuotePosition::End } => {
+                write!(f, "missing closing quote")
+            }
+            UnescapeErrorKind::InputTooLong { len, limit } => {
+                write!(f, "input of {len} bytes exceeds the limit of {limit} bytes")
+            }
         }
     }
 }
 
+#[allow(dead_code)]
+pub struct UnescapeErrorWithSource<'a> {
+    err: &'a UnescapeError,
+    source: &'a str,
+}
+
+impl<'a> fmt::Display for UnescapeErrorWithSource<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.err)?;
+        if !f.alternate() {
+            return Ok(());
+        }
+
+        let width_config = WidthConfig::default();
+        let start_col = self.err.display_column(self.source, width_config);
+        let end_col = column_of_byte(self.source, self.err.span.end, width_config);
+        write!(
+            f,
+            "\n{}\n{}{}",
+            self.source,
+            " ".repeat(start_col),
+            "^".repeat((end_col - start_col).max(1))
+        )
+    }
+}
+
 enum DecodedCodepoint {
     Char(char),
     LowSurrogate(u16),
     HighSurrogate(u16),
 }
 
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum EscapeStyle {
+    // Standard JSON escapes.
+    #[default]
+    Json,
+    // Like Json, but control characters without a single character escape
+    // are written C-style as "\xHH" instead of "\u00HH".
+    CHex,
+    // Like Json, but characters outside the Basic Multilingual Plane are
+    // written as a single "\u{XXXXX}" escape instead of a surrogate pair.
+    // Unescaping also accepts "\xHH" escapes.
+    Json5,
+    // Like Json, but only quotes, backslashes and control characters are
+    // escaped; all other characters are passed through unchanged.
+    Minimal,
+}
+
+// The case of the hex digits in escapes written by the escaper.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum HexCase {
+    #[default]
+    Lower,
+    #[allow(dead_code)]
+    Upper,
+}
+
+// How escape_for_display shows tabs and newlines.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+#[allow(dead_code)]
+pub enum WhitespaceRendering {
+    // As "\t" and "\n", like escape_json_string.
+    #[default]
+    Escape,
+    // Tabs are expanded to spaces, up to the next multiple of [tab_width]
+    // columns, and newlines are left as actual line breaks.
+    Expand { tab_width: usize },
+    // As the symbols '→' and '↵'.
+    Symbol,
+    // Tabs are passed through as actual tabs, along with runs of spaces,
+    // while newlines are still escaped, so each string stays on one line.
+    Preserve,
+}
+
+// How to handle a byte order mark (U+FEFF, a zero width no-break space) at
+// the start of an unescaped string.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum BomPolicy {
+    #[default]
+    Keep,
+    #[allow(dead_code)]
+    Strip,
+    // Replace it with a visible "\ufeff" escape.
+    #[allow(dead_code)]
+    Escape,
+}
+
+// How control characters are shown when they're left escaped.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum ControlRendering {
+    // As the escape sequence they were written with, or "\u00XX" if they
+    // weren't escaped in the source.
+    #[default]
+    Escape,
+    // As their abbreviated name, e.g., "<NUL>" or "<ESC>". C1 controls,
+    // which don't have well known abbreviations, are shown as "<U+XXXX>".
+    #[allow(dead_code)]
+    Named,
+}
+
+// How to handle a surrogate escape that isn't part of a valid surrogate
+// pair, i.e., a low surrogate that isn't preceded by a high surrogate, or a
+// high surrogate that isn't followed by a low surrogate.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum SurrogatePolicy {
+    // Fail with an UnescapeError.
+    #[default]
+    Error,
+    // Substitute [replacement] for the unpaired surrogate and continue. This
+    // is usually U+FFFD (the Unicode replacement character), but can be any
+    // char, e.g., '?' for a marker that's easy to type and search for.
+    #[allow(dead_code)]
+    Replace { replacement: char },
+    // Drop the unpaired surrogate and continue. If a high surrogate is
+    // followed by another high surrogate, only the first one is unpaired,
+    // so only it is dropped; the second may still pair with a following low
+    // surrogate.
+    #[allow(dead_code)]
+    Skip,
+}
+
+// The two char escapes (a '\' followed by a letter or symbol) recognized
+// when unescaping, and used for control characters when escaping with
+// escape_json_string_with. The default is JSON's set: "\"", "\\", "\/",
+// "\b", "\f", "\n", "\r" and "\t". Other dialects add more, e.g., "\a"
+// (bell), "\v" (vertical tab) or "\0" (NUL).
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ShortEscapeTable {
+    // Pairs of (escape letter, char it stands for).
+    entries: Cow<'static, [(char, char)]>,
+}
+
+const JSON_SHORT_ESCAPES: [(char, char); 8] = [
+    ('"', '"'),
+    ('\\', '\\'),
+    ('/', '/'),
+    ('b', '\x08'),
+    ('f', '\x0c'),
+    ('n', '\n'),
+    ('r', '\r'),
+    ('t', '\t'),
+];
+
+impl Default for ShortEscapeTable {
+    fn default() -> Self {
+        ShortEscapeTable {
+            entries: Cow::Borrowed(&JSON_SHORT_ESCAPES),
+        }
+    }
+}
+
+impl ShortEscapeTable {
+    // Returns the table with "\[letter]" standing for [ch], replacing any
+    // existing entry for [letter]. Entries take precedence over the other
+    // escapes, so an entry for 'u' would shadow "\uXXXX" escapes.
+    #[allow(dead_code)]
+    pub fn with(mut self, letter: char, ch: char) -> ShortEscapeTable {
+        let entries = self.entries.to_mut();
+        entries.retain(|&(existing, _)| existing != letter);
+        entries.push((letter, ch));
+        self
+    }
+
+    // The char "\[letter]" stands for, if any.
+    pub fn decode(&self, letter: char) -> Option<char> {
+        self.entries
+            .iter()
+            .find(|&&(existing, _)| existing == letter)
+            .map(|&(_, ch)| ch)
+    }
+
+    // The letter of an escape for [ch], if any. If there are several, the
+    // first one added is returned.
+    pub fn encode(&self, ch: char) -> Option<char> {
+        self.entries
+            .iter()
+            .find(|&&(_, existing)| existing == ch)
+            .map(|&(letter, _)| letter)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnescapeConfig {
+    // If true, Unicode control characters will be left escaped.
+    pub escape_control_characters: bool,
+    pub control_rendering: ControlRendering,
+    // Determines which escape sequences are recognized.
+    pub style: EscapeStyle,
+    pub bom_policy: BomPolicy,
+    // If true, accept "\e" as an escape for ESC, as some tools emit. Other
+    // unknown escapes are always errors.
+    pub lenient: bool,
+    pub surrogate_policy: SurrogatePolicy,
+    // If true, treat "\/" as an invalid escape. It's valid JSON, but never
+    // necessary, so strict linters may want to flag it.
+    pub reject_escaped_slash: bool,
+    // If true, bidirectional control characters (see [is_bidi_control]) are
+    // left escaped, like control characters, even if
+    // [escape_control_characters] is false.
+    pub escape_bidi_controls: bool,
+    // If true, invisible zero-width characters (see [is_zero_width]) are
+    // left escaped, so they can be seen.
+    pub escape_zero_width: bool,
+    // If true, an unmatched high surrogate error is always reported at the
+    // char right after the high surrogate's escape. Otherwise, if the high
+    // surrogate is followed by another "\uXXXX" escape, the error is reported
+    // after that escape, once it's been read.
+    pub eager_surrogate_errors: bool,
+    // If true, a raw (unescaped) control character in U+0000 to U+001F is an
+    // error, as RFC 8259 requires. Otherwise, it's accepted, and treated
+    // like any other literal char.
+    pub strict_rfc8259: bool,
+    // If true, control characters that weren't escaped in the source, and
+    // have a short escape in [short_escapes] (by default, "\b", "\f", "\n",
+    // "\r" and "\t"), are left escaped with it when rendered with
+    // ControlRendering::Escape, rather than as "\u00XX". Other control
+    // characters still use "\u00XX".
+    pub prefer_short_escapes: bool,
+    pub short_escapes: ShortEscapeTable,
+    // The maximum length of the escaped input, in bytes. Longer input is
+    // rejected up front, without scanning any of it, so a single huge
+    // string can't tie up the viewer.
+    pub max_input_len: Option<usize>,
+}
+
+impl Default for UnescapeConfig {
+    fn default() -> Self {
+        UnescapeConfig {
+            escape_control_characters: true,
+            control_rendering: ControlRendering::Escape,
+            style: EscapeStyle::Json,
+            bom_policy: BomPolicy::Keep,
+            lenient: false,
+            surrogate_policy: SurrogatePolicy::Error,
+            reject_escaped_slash: false,
+            escape_bidi_controls: false,
+            escape_zero_width: false,
+            eager_surrogate_errors: false,
+            strict_rfc8259: false,
+            prefer_short_escapes: false,
+            short_escapes: ShortEscapeTable::default(),
+            max_input_len: None,
+        }
+    }
+}
+
 // Unescapes a syntactically valid JSON string into a valid UTF-8 string.
-// If [escape_control_characters] is true, Unicode control characters will be
-// be escaped.
+// If [config.escape_control_characters] is true, Unicode control characters
+// will be escaped.
 //
 // This makes the assumption that the only characters following a '\' are:
 // - single character escapes: "\/bfnrt
 // - a unicode character escape: uxxxx
+// - with EscapeStyle::CHex or EscapeStyle::Json5, a hex byte escape: xHH
+// - with EscapeStyle::Json5, a code point escape: u{x...}
+// - with [config.lenient], an ESC escape: e
+//
+// Any other escape results in an error.
 //
 // Unicode escapes are exactly four characters, and essentially represent
 // UTF-16 encoded codepoints.
@@ -54,246 +432,2187 @@ enum DecodedCodepoint {
 // For more information, and a walkthrough of how to convert the surrogate pairs
 // back into an actual char, see:
 // https://en.wikipedia.org/wiki/UTF-16#Code_points_from_U+010000_to_U+10FFFF
-fn unescape_json_string(s: &str, escape_control_characters: bool) -> Result<String, UnescapeError> {
-    let mut chars = s.chars();
+pub fn unescape_json_string(s: &str, config: &UnescapeConfig) -> Result<String, UnescapeError> {
+    if let Some(limit) = config.max_input_len.filter(|&limit| s.len() > limit) {
+        return Err(UnescapeError {
+            index: 1,
+            span: 0..s.len(),
+            error: UnescapeErrorKind::InputTooLong { len: s.len(), limit },
+        });
+    }
+
+    let mut unescaped = String::with_capacity(s.len());
+    let mut iter = UnescapeIter::new(s, config);
+    loop {
+        // Most strings are mostly text that's copied as is, so copy each run
+        // of it in one go, rather than a char at a time.
+        unescaped.push_str(iter.take_verbatim_run());
+        match iter.next() {
+            Some(decoded) => push_unescaped_char(&mut unescaped, s, &decoded?, config),
+            None => break,
+        }
+    }
+    apply_bom_policy(&mut unescaped, config.bom_policy);
+    Ok(unescaped)
+}
+
+// Like unescape_json_string, for escaped input that's known to be entirely
+// ASCII, e.g., JSON produced with every non-ASCII char written as "\uXXXX".
+// This skips UTF-8 validation of [s]; a non-ASCII byte is an error instead.
+#[allow(dead_code)]
+pub fn unescape_ascii(s: &[u8], config: &UnescapeConfig) -> Result<String, UnescapeError> {
+    if let Some(pos) = s.iter().position(|byte| !byte.is_ascii()) {
+        return Err(UnescapeError {
+            // Every char before it is a single byte.
+            index: pos + 1,
+            span: pos..pos + 1,
+            error: UnescapeErrorKind::NonAsciiByte(s[pos]),
+        });
+    }
+
+    // SAFETY: ASCII is always valid UTF-8.
+    let s = unsafe { std::str::from_utf8_unchecked(s) };
+    unescape_json_string(s, config)
+}
+
+// Like safe_unescape_json_string, but fails as soon as a char of the result
+// would be non-ASCII, whether it was escaped (e.g., "\u20ac", or a surrogate
+// pair) or not, e.g., to check that object keys are plain ASCII.
+#[allow(dead_code)]
+pub fn unescape_require_ascii(s: &str) -> Result<String, UnescapeError> {
+    let config = UnescapeConfig::default();
     let mut unescaped = String::with_capacity(s.len());
-    let mut index = 1;
+    for decoded in UnescapeIter::new(s, &config) {
+        let decoded = decoded?;
+        if !decoded.ch.is_ascii() {
+            return Err(UnescapeError {
+                index: s[..decoded.source.start].chars().count() + 1,
+                span: decoded.source,
+                error: UnescapeErrorKind::NonAsciiNotAllowed { ch: decoded.ch },
+            });
+        }
+        push_unescaped_char(&mut unescaped, s, &decoded, &config);
+    }
+    Ok(unescaped)
+}
+
+// Repeatedly unescapes [s], e.g., a JSON string that was itself escaped
+// into another JSON string, until the result stops changing, or [max_rounds]
+// rounds have been done. Only errors from the first round are returned; if
+// a later round fails, the result of the previous round isn't an escaped
+// string after all, so it's returned as is.
+#[allow(dead_code)]
+pub fn unescape_fully(
+    s: &str,
+    max_rounds: usize,
+    config: &UnescapeConfig,
+) -> Result<String, UnescapeError> {
+    if max_rounds == 0 {
+        return Ok(s.to_owned());
+    }
+
+    let mut unescaped = unescape_json_string(s, config)?;
+    if unescaped == s {
+        return Ok(unescaped);
+    }
+    for _ in 1..max_rounds {
+        match unescape_json_string(&unescaped, config) {
+            Ok(next) if next != unescaped => unescaped = next,
+            _ => break,
+        }
+    }
+    Ok(unescaped)
+}
+
+// Like unescape_json_string, but only unescapes the first [n] logical chars
+// of [s], e.g., for a preview of a huge string. A surrogate pair counts as a
+// single char, as does a control character that's left escaped. Returns the
+// unescaped prefix, and whether [s] was truncated.
+#[allow(dead_code)]
+pub fn unescape_prefix(
+    s: &str,
+    n: usize,
+    config: &UnescapeConfig,
+) -> Result<(String, bool), UnescapeError> {
+    let mut unescaped = String::new();
+    let mut iter = UnescapeIter::new(s, config);
+    let mut num_chars = 0;
+    while num_chars < n {
+        let decoded = match iter.next() {
+            Some(decoded) => decoded?,
+            None => break,
+        };
+        // A BOM that's going to be stripped doesn't count.
+        if !(unescaped.is_empty() && is_stripped_bom(&decoded, config)) {
+            num_chars += 1;
+        }
+        push_unescaped_char(&mut unescaped, s, &decoded, config);
+    }
+    apply_bom_policy(&mut unescaped, config.bom_policy);
+    Ok((unescaped, !iter.chars.as_str().is_empty()))
+}
+
+// Returns the number of logical chars unescape_json_string(s, config) would
+// produce, without building the unescaped string, e.g., to lay out a value
+// before unescaping it. As in unescape_prefix, a surrogate pair counts as a
+// single char, as does a control character that's left escaped. Escapes are
+// still validated, so this fails whenever unescape_json_string would.
+#[allow(dead_code)]
+pub fn unescaped_char_count(s: &str, config: &UnescapeConfig) -> Result<usize, UnescapeError> {
+    let mut count = 0;
+    for (i, decoded) in UnescapeIter::new(s, config).enumerate() {
+        let decoded = decoded?;
+        if !(i == 0 && is_stripped_bom(&decoded, config)) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// Returns whether [a] and [b] unescape to the same value, e.g., "\u0041"
+// and "A", without building either unescaped string. This stops at the first
+// char that differs, so an error later in either string isn't reported.
+//
+// Control characters are compared by value, regardless of how they'd be
+// rendered, so "\u001b" and "\u001B" are equal.
+#[allow(dead_code)]
+pub fn unescaped_eq(a: &str, b: &str, config: &UnescapeConfig) -> Result<bool, UnescapeError> {
+    let mut a_chars = UnescapeIter::new(a, config);
+    let mut b_chars = UnescapeIter::new(b, config);
+    loop {
+        match (a_chars.next().transpose()?, b_chars.next().transpose()?) {
+            (None, None) => return Ok(true),
+            (Some(a_char), Some(b_char)) if a_char.ch == b_char.ch => {}
+            _ => return Ok(false),
+        }
+    }
+}
+
+// Iterates over the chars decoded from [s], along with the byte range in [s]
+// each one was decoded from, e.g., 6 bytes for "\u00e9", or 12 for a
+// surrogate pair. This is the inverse of [escape_chunks]. The chars are the
+// actual decoded values; control characters are never left escaped.
+//
+// The ranges cover all of [s], unless a surrogate is skipped because of
+// SurrogatePolicy::Skip. Iteration stops after the first error.
+#[allow(dead_code)]
+pub fn unescape_indices<'a>(
+    s: &'a str,
+    config: &'a UnescapeConfig,
+) -> impl Iterator<Item = Result<(Range<usize>, char), UnescapeError>> + 'a {
+    UnescapeIter::new(s, config).map(|decoded| decoded.map(|decoded| (decoded.source, decoded.ch)))
+}
+
+fn apply_bom_policy(unescaped: &mut String, bom_policy: BomPolicy) {
+    if unescaped.starts_with('\u{FEFF}') {
+        let bom_len = '\u{FEFF}'.len_utf8();
+        match bom_policy {
+            BomPolicy::Keep => {}
+            BomPolicy::Strip => unescaped.replace_range(..bom_len, ""),
+            BomPolicy::Escape => unescaped.replace_range(..bom_len, "\\ufeff"),
+        }
+    }
+}
+
+// Whether [decoded], if it's the first char of the unescaped string, is a
+// BOM that apply_bom_policy strips, rather than one that's left escaped.
+fn is_stripped_bom(decoded: &UnescapedChar, config: &UnescapeConfig) -> bool {
+    decoded.ch == '\u{FEFF}'
+        && config.bom_policy == BomPolicy::Strip
+        && (matches!(decoded.control_escape, ControlEscape::Never)
+            || !leave_escaped(decoded.ch, config))
+}
+
+// A single logical char decoded from an escaped string.
+struct UnescapedChar {
+    ch: char,
+    // The byte range of the escaped string the char was decoded from.
+    source: Range<usize>,
+    control_escape: ControlEscape,
+}
+
+// How a decoded control character is shown when control characters are
+// left escaped.
+enum ControlEscape {
+    // Never left escaped, e.g., "\n" and "\t".
+    Never,
+    // As the escape sequence it was decoded from.
+    Source,
+    // As a lowercase "\u00xx" escape, the standard JSON equivalent of the
+    // non-standard escape it was decoded from, e.g., "\e".
+    NonStandard,
+    // As a "\u00XX" escape, for chars that weren't escaped in the source.
+    Synthesized,
+}
+
+// Decodes the logical chars of an escaped string one at a time. See
+// unescape_json_string for the escapes that are recognized. After an error,
+// the iterator is exhausted.
+struct UnescapeIter<'a> {
+    s: &'a str,
+    chars: std::str::Chars<'a>,
+    // The position of the last char consumed, counting the opening quote,
+    // for error messages.
+    index: usize,
+    config: &'a UnescapeConfig,
+}
+
+impl<'a> UnescapeIter<'a> {
+    fn new(s: &'a str, config: &'a UnescapeConfig) -> UnescapeIter<'a> {
+        UnescapeIter {
+            s,
+            chars: s.chars(),
+            index: 1,
+            config,
+        }
+    }
 
-    while let Some(ch) = chars.next() {
-        index += 1;
-        if ch != '\\' {
-            if escape_control_characters && is_control(ch) {
-                unescaped.push_str("\\u00");
-                write!(unescaped, "{:02X}", ch as u32).unwrap();
-            } else {
-                unescaped.push(ch);
+    fn offset(&self) -> usize {
+        self.s.len() - self.chars.as_str().len()
+    }
+
+    // Consumes the longest run of chars that would each be pushed onto the
+    // output unchanged, i.e., that aren't escapes, and aren't left escaped or
+    // rejected because of the config, and returns it.
+    fn take_verbatim_run(&mut self) -> &'a str {
+        let rest = self.chars.as_str();
+        let mut len = 0;
+        for ch in rest.chars() {
+            let verbatim = ch != '\\'
+                && !(self.config.strict_rfc8259 && (ch as u32) < 0x20)
+                && !leave_escaped(ch, self.config);
+            if !verbatim {
+                break;
             }
-            continue;
+            len += ch.len_utf8();
+            self.index += 1;
         }
+        self.chars = rest[len..].chars();
+        &rest[..len]
+    }
 
-        let escaped = chars.next().unwrap();
-        index += 1;
+    // Decodes the escape sequence starting at byte [start], after its '\' has
+    // been consumed. Returns None if the escape was skipped.
+    fn next_escape(
+        &mut self,
+        start: usize,
+    ) -> Result<Option<(char, ControlEscape)>, UnescapeError> {
+        let escaped = match self.chars.next() {
+            Some(escaped) => escaped,
+            None => {
+                return Err(UnescapeError {
+                    index: self.index - 1,
+                    span: start..self.offset(),
+                    error: UnescapeErrorKind::TruncatedEscape,
+                });
+            }
+        };
+        self.index += 1;
 
-        match escaped {
-            '"' => unescaped.push('"'),
-            '\\' => unescaped.push('\\'),
-            '/' => unescaped.push('/'),
-            // '\b' is backspace, a control character.
-            'b' => {
-                if escape_control_characters {
-                    unescaped.push_str("\\b");
-                } else {
-                    unescaped.push(0x08 as char);
-                }
+        let short_escape = match escaped {
+            '/' if self.config.reject_escaped_slash => None,
+            escaped => self.config.short_escapes.decode(escaped),
+        };
+        if let Some(ch) = short_escape {
+            // Whitespace is always unescaped, but other control characters,
+            // like '\b' (backspace), may be left escaped.
+            let control_escape = match ch {
+                '\x0c' | '\n' | '\r' | '\t' => ControlEscape::Never,
+                ch if is_control(ch) => ControlEscape::Source,
+                _ => ControlEscape::Never,
+            };
+            return Ok(Some((ch, control_escape)));
+        }
+
+        let decoded = match escaped {
+            'x' if matches!(self.config.style, EscapeStyle::CHex | EscapeStyle::Json5) => {
+                let byte = match (
+                    self.chars.next().and_then(hex_char_to_int),
+                    self.chars.next().and_then(hex_char_to_int),
+                ) {
+                    (Some(high), Some(low)) => high * 0x10 + low,
+                    _ => {
+                        return Err(UnescapeError {
+                            index: self.index - 2,
+                            span: start..self.offset(),
+                            error: UnescapeErrorKind::InvalidHexEscape(
+                                self.s[start..self.offset()].to_owned(),
+                            ),
+                        });
+                    }
+                };
+                self.index += 2;
+                (char::from_u32(byte as u32).unwrap(), ControlEscape::Source)
+            }
+            'u' if self.config.style == EscapeStyle::Json5
+                && self.chars.as_str().starts_with('{') =>
+            {
+                // Consume the '{', then one to six hex digits and the '}'.
+                self.chars.next();
+                let mut codepoint = 0;
+                let mut num_digits = 0;
+                let ch = loop {
+                    match self.chars.next() {
+                        Some('}') if num_digits > 0 => break char::from_u32(codepoint),
+                        Some(c) if num_digits < 6 => match c.to_digit(16) {
+                            Some(digit) => {
+                                codepoint = codepoint * 0x10 + digit;
+                                num_digits += 1;
+                            }
+                            None => break None,
+                        },
+                        _ => break None,
+                    }
+                };
+                // Also covers code points that aren't chars, like surrogates.
+                let ch = ch.ok_or_else(|| UnescapeError {
+                    index: self.index - 2,
+                    span: start..self.offset(),
+                    error: UnescapeErrorKind::InvalidHexEscape(
+                        self.s[start..self.offset()].to_owned(),
+                    ),
+                })?;
+                self.index += num_digits + 2;
+                (ch, ControlEscape::Source)
             }
-            'f' => unescaped.push('\x0c'),
-            'n' => unescaped.push('\n'),
-            'r' => unescaped.push('\r'),
-            't' => unescaped.push('\t'),
             'u' => {
-                let (codepoint, codepoint_chars) = parse_codepoint_from_chars(&mut chars);
-                index += 4;
+                let (codepoint, codepoint_chars) = parse_codepoint_from_chars(&mut self.chars)
+                    .map_err(|error| UnescapeError {
+                        index: self.index - 2,
+                        span: start..self.offset(),
+                        error,
+                    })?;
+                self.index += 4;
 
                 match decode_codepoint(codepoint) {
-                    DecodedCodepoint::Char(ch) => {
-                        if escape_control_characters && is_control(ch) {
-                            unescaped.push_str("\\u");
-                            unescaped.push(codepoint_chars[0] as char);
-                            unescaped.push(codepoint_chars[1] as char);
-                            unescaped.push(codepoint_chars[2] as char);
-                            unescaped.push(codepoint_chars[3] as char);
-                        } else {
-                            unescaped.push(ch)
-                        }
-                    }
+                    DecodedCodepoint::Char(ch) => (ch, ControlEscape::Source),
                     DecodedCodepoint::LowSurrogate(_) => {
-                        return Err(UnescapeError {
-                            index: index - 6,
-                            codepoint_chars,
-                            error: UnicodeError::UnexpectedLowSurrogate,
+                        return self.unpaired_surrogate(UnescapeError {
+                            index: self.index - 6,
+                            span: start..self.offset(),
+                            error: UnescapeErrorKind::UnexpectedLowSurrogate { codepoint_chars },
                         });
                     }
-                    DecodedCodepoint::HighSurrogate(hs) => match (chars.next(), chars.next()) {
-                        (Some('\\'), Some('u')) => {
-                            index += 2;
-                            let (codepoint, _) = parse_codepoint_from_chars(&mut chars);
-                            index += 4;
-
-                            match decode_codepoint(codepoint) {
-                                DecodedCodepoint::LowSurrogate(ls) => {
-                                    let codepoint = (hs as u32) * 0x400 + (ls as u32) + 0x10000;
-                                    unescaped.push(char::from_u32(codepoint).unwrap());
-                                }
-                                _ => {
-                                    return Err(UnescapeError {
-                                        index,
+                    DecodedCodepoint::HighSurrogate(hs) => {
+                        // Look ahead for the low surrogate, without consuming
+                        // anything if it isn't there.
+                        let mut rest = self.chars.clone();
+                        let low_surrogate = match (rest.next(), rest.next()) {
+                            (Some('\\'), Some('u')) => match parse_codepoint_from_chars(&mut rest)
+                                .map(|(codepoint, _)| decode_codepoint(codepoint))
+                            {
+                                Ok(DecodedCodepoint::LowSurrogate(ls)) => Ok(ls),
+                                _ if self.config.eager_surrogate_errors => Err(self.index),
+                                _ => Err(self.index + 6),
+                            },
+                            _ => Err(self.index),
+                        };
+
+                        match low_surrogate {
+                            Ok(ls) => {
+                                self.chars = rest;
+                                self.index += 6;
+                                (surrogate_pair_char(hs, ls), ControlEscape::Never)
+                            }
+                            Err(index) => {
+                                return self.unpaired_surrogate(UnescapeError {
+                                    index,
+                                    span: start..self.offset(),
+                                    error: UnescapeErrorKind::UnmatchedHighSurrogate {
                                         codepoint_chars,
-                                        error: UnicodeError::UnmatchedHighSurrogate,
-                                    });
-                                }
+                                    },
+                                });
                             }
                         }
-                        _ => {
-                            return Err(UnescapeError {
-                                index,
-                                codepoint_chars,
-                                error: UnicodeError::UnmatchedHighSurrogate,
-                            });
-                        }
-                    },
+                    }
+                }
+            }
+            // '\e' is a common non-standard escape for ESC.
+            'e' if self.config.lenient => ('\x1b', ControlEscape::NonStandard),
+            _ => {
+                return Err(UnescapeError {
+                    index: self.index - 2,
+                    span: start..self.offset(),
+                    error: UnescapeErrorKind::InvalidEscape(escaped),
+                });
+            }
+        };
+        Ok(Some(decoded))
+    }
+
+    // Handles an unpaired surrogate according to [config.surrogate_policy].
+    fn unpaired_surrogate(
+        &self,
+        err: UnescapeError,
+    ) -> Result<Option<(char, ControlEscape)>, UnescapeError> {
+        match self.config.surrogate_policy {
+            SurrogatePolicy::Error => Err(err),
+            SurrogatePolicy::Replace { replacement } => {
+                Ok(Some((replacement, ControlEscape::Never)))
+            }
+            SurrogatePolicy::Skip => Ok(None),
+        }
+    }
+}
+
+impl<'a> Iterator for UnescapeIter<'a> {
+    type Item = Result<UnescapedChar, UnescapeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let ch = self.chars.next()?;
+            let start = self.offset() - ch.len_utf8();
+            self.index += 1;
+
+            if self.config.strict_rfc8259 && (ch as u32) < 0x20 {
+                self.chars = self.s[self.s.len()..].chars();
+                return Some(Err(UnescapeError {
+                    index: self.index - 1,
+                    span: start..start + 1,
+                    error: UnescapeErrorKind::UnescapedControl(ch),
+                }));
+            }
+
+            if ch != '\\' {
+                return Some(Ok(UnescapedChar {
+                    ch,
+                    source: start..self.offset(),
+                    control_escape: ControlEscape::Synthesized,
+                }));
+            }
+
+            match self.next_escape(start) {
+                Ok(Some((ch, control_escape))) => {
+                    return Some(Ok(UnescapedChar {
+                        ch,
+                        source: start..self.offset(),
+                        control_escape,
+                    }));
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    self.chars = self.s[self.s.len()..].chars();
+                    return Some(Err(err));
                 }
             }
-            _ => panic!("Unexpected escape character in JSON string: {}", ch),
         }
     }
+}
 
-    Ok(unescaped)
+// Pushes a decoded char onto [unescaped], unless it's a control character
+// that should be left escaped, in which case it's rendered according to
+// [config.control_rendering]. [s] is the escaped string the char was
+// decoded from.
+fn push_unescaped_char(
+    unescaped: &mut String,
+    s: &str,
+    decoded: &UnescapedChar,
+    config: &UnescapeConfig,
+) {
+    let ch = decoded.ch;
+    let escape = match decoded.control_escape {
+        ControlEscape::Never => {
+            unescaped.push(ch);
+            return;
+        }
+        ControlEscape::Source => Some(Cow::Borrowed(&s[decoded.source.clone()])),
+        ControlEscape::NonStandard => Some(Cow::Owned(format!("\\u{:04x}", ch as u32))),
+        ControlEscape::Synthesized => None,
+    };
+
+    if !leave_escaped(ch, config) {
+        unescaped.push(ch);
+        return;
+    }
+
+    match config.control_rendering {
+        ControlRendering::Escape => match escape {
+            Some(escape) => unescaped.push_str(&escape),
+            None => match config.short_escapes.encode(ch).filter(|_| config.prefer_short_escapes) {
+                Some(letter) => write!(unescaped, "\\{letter}").unwrap(),
+                None => write!(unescaped, "\\u{:04X}", ch as u32).unwrap(),
+            },
+        },
+        ControlRendering::Named => match control_name(ch) {
+            Some(name) => write!(unescaped, "<{name}>").unwrap(),
+            None => write!(unescaped, "<U+{:04X}>", ch as u32).unwrap(),
+        },
+    }
+}
+
+// Whether [ch] is left escaped, rather than unescaped, when it wasn't
+// escaped with a short escape in the source.
+fn leave_escaped(ch: char, config: &UnescapeConfig) -> bool {
+    (config.escape_control_characters && is_control(ch))
+        || (config.escape_bidi_controls && is_bidi_control(ch))
+        || (config.escape_zero_width && is_zero_width(ch))
+}
+
+// Returns the standard abbreviation of a C0 control character or DEL.
+fn control_name(ch: char) -> Option<&'static str> {
+    const C0_NAMES: [&str; 32] = [
+        "NUL", "SOH", "STX", "ETX", "EOT", "ENQ", "ACK", "BEL", "BS", "HT", "LF", "VT", "FF",
+        "CR", "SO", "SI", "DLE", "DC1", "DC2", "DC3", "DC4", "NAK", "SYN", "ETB", "CAN", "EM",
+        "SUB", "ESC", "FS", "GS", "RS", "US",
+    ];
+    match ch as u32 {
+        codepoint @ 0x00..=0x1F => Some(C0_NAMES[codepoint as usize]),
+        0x7F => Some("DEL"),
+        _ => None,
+    }
 }
 
 // Unescapes a syntactically valid JSON string into a valid UTF-8 string, but
 // leaves control characters escaped.
 pub fn safe_unescape_json_string(s: &str) -> Result<String, UnescapeError> {
-    unescape_json_string(s, true)
+    unescape_json_string(s, &UnescapeConfig::default())
 }
 
 // Unescapes a syntactically valid JSON string into a valid UTF-8 string, including
 // control characters.
-#[allow(dead_code)] // Only used with #[cfg(feature = "sexp")], but we want to write
-                    // regular tests for it
+// Only used for sexp output, but we also build it for tests.
+#[cfg(any(test, feature = "sexp"))]
 pub fn unsafe_unescape_json_string(s: &str) -> Result<String, UnescapeError> {
-    unescape_json_string(s, false)
+    let config = UnescapeConfig {
+        escape_control_characters: false,
+        ..UnescapeConfig::default()
+    };
+    unescape_json_string(s, &config)
+}
+
+// Returns whether safe_unescape_json_string(s) would return [s] unchanged,
+// so callers can avoid storing a separate unescaped copy. That's the case
+// when every char is either a literal non-control char, or a control char
+// escaped in a form that safe unescaping leaves as is (e.g., "\u001b" or
+// "\b", but not "\n", which gets unescaped). Raw control chars get
+// re-escaped, and invalid escapes are errors, so both return false.
+#[allow(dead_code)]
+pub fn safe_unescape_is_noop(s: &str) -> bool {
+    let config = UnescapeConfig::default();
+    UnescapeIter::new(s, &config).all(|decoded| match decoded {
+        Ok(decoded) => match decoded.control_escape {
+            ControlEscape::Source => is_control(decoded.ch),
+            _ => !is_control(decoded.ch) && !s[decoded.source].starts_with('\\'),
+        },
+        Err(_) => false,
+    })
 }
 
 pub fn unescape_json_string_unwrap(s: &str) -> String {
-    match unescape_json_string(s, true) {
+    unescape_json_string_or_log(s, |_| {})
+}
+
+// Like unescape_json_string_unwrap, but calls [on_err] with the error before
+// falling back to [s] itself, so that callers can log or count strings that
+// couldn't be unescaped.
+pub fn unescape_json_string_or_log(s: &str, on_err: impl FnOnce(&UnescapeError)) -> String {
+    match safe_unescape_json_string(s) {
         Ok(unescaped) => unescaped,
-        Err(_) => s.to_owned()
+        Err(err) => {
+            on_err(&err);
+            s.to_owned()
+        }
     }
 }
 
-fn escape_json_unicode(
-        escaped: &mut String, c: char,
-        backslash: &str, utf16_buf: &mut [u16]) {
+fn escape_json_unicode<W: Write>(
+        escaped: &mut W, c: char, backslash: &str,
+        hex_case: HexCase, utf16_buf: &mut [u16]) -> fmt::Result {
     let encoded = c.encode_utf16(utf16_buf);
     for utf16 in encoded {
-        *escaped += backslash;
-        write!(escaped, "u{:04x}", utf16).unwrap();
+        escaped.write_str(backslash)?;
+        match hex_case {
+            HexCase::Lower => write!(escaped, "u{:04x}", utf16)?,
+            HexCase::Upper => write!(escaped, "u{:04X}", utf16)?,
+        }
     }
+    Ok(())
 }
 
-// Source: https://www.reddit.com/r/rust/comments/i4bg0q/comment/g0hl58g/?utm_source=share&utm_medium=web2x&context=3
 pub fn escape_json_string(src: &str) -> String {
+    escape_with_style(src, EscapeStyle::Json)
+}
+
+// Like escape_json_string, but takes raw bytes that may not be valid UTF-8.
+// Every invalid sequence is escaped as U+FFFD, and an encoded surrogate code
+// point (e.g., the bytes ED A0 80) counts as a single invalid sequence. For
+// valid UTF-8 [src], this is the same as escape_json_string.
+#[allow(dead_code)]
+pub fn escape_json_string_lenient(src: &[u8]) -> String {
+    let mut bytes = src;
+    let mut escaped = String::with_capacity(bytes.len());
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                escaped.push_str(&escape_json_string(valid));
+                return escaped;
+            }
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                escaped.push_str(&escape_json_string(std::str::from_utf8(valid).unwrap()));
+                escaped.push_str(escape_char('\u{FFFD}', EscapeStyle::Json).as_str());
+                let invalid_len = match rest {
+                    [0xED, 0xA0..=0xBF, 0x80..=0xBF, ..] => 3,
+                    _ => err.error_len().unwrap_or(rest.len()),
+                };
+                bytes = &rest[invalid_len..];
+            }
+        }
+    }
+}
+
+fn escape_with_style(src: &str, style: EscapeStyle) -> String {
     let mut escaped = String::with_capacity(src.len());
     let mut utf16_buf = [0u16; 2];
     for c in src.chars() {
-        match c {
-            '\x08' => { escaped += "\\b" },
-            '\x0c' => { escaped += "\\f" },
-            '\n' => { escaped += "\\n" },
-            '\r' => { escaped += "\\r" },
-            '\t' => { escaped += "\\t" },
-            '"' => { escaped += "\\\"" },
-            '\\' => { escaped += "\\" },
-            ' ' => { escaped += " " },
-            c if c.is_ascii_graphic() => escaped.push(c),
-            c => escape_json_unicode(&mut escaped, c, "\\", &mut utf16_buf),
-        }
+        escape_json_char(&mut escaped, c, style, HexCase::Lower, &mut utf16_buf).unwrap();
     }
     escaped
 }
 
-pub fn escape_unicode_for_regex(src: &str) -> String {
-    let mut escaped = String::with_capacity(src.len());
+// Like escape_json_string, but also wraps the escaped string in double
+// quotes, producing a complete JSON string literal.
+#[allow(dead_code)]
+pub fn to_json_literal(src: &str) -> String {
+    let mut literal = String::with_capacity(src.len() + 2);
     let mut utf16_buf = [0u16; 2];
+    literal.push('"');
     for c in src.chars() {
-        match c {
-            c if c.is_ascii() => escaped.push(c),
-            c => escape_json_unicode(&mut escaped, c, "\\\\", &mut utf16_buf),
+        escape_json_char(&mut literal, c, EscapeStyle::Json, HexCase::Lower, &mut utf16_buf)
+            .unwrap();
+    }
+    literal.push('"');
+    literal
+}
+
+// The inverse of to_json_literal: strips the double quotes around the JSON
+// string literal [literal], and unescapes its contents as
+// safe_unescape_json_string does. It's an error if either quote is missing,
+// rather than unescaping whatever is there. Error indices count from the
+// opening quote, as usual.
+#[allow(dead_code)]
+pub fn from_json_literal(literal: &str) -> Result<String, UnescapeError> {
+    let missing_quote = |position| {
+        let (index, offset) = match position {
+            QuotePosition::Start => (1, 0),
+            QuotePosition::End => (literal.chars().count() + 1, literal.len()),
+        };
+        UnescapeError {
+            index,
+            span: offset..offset,
+            error: UnescapeErrorKind::MissingQuote { position },
+        }
+    };
+
+    let rest = literal.strip_prefix('"').ok_or_else(|| missing_quote(QuotePosition::Start))?;
+    let body = rest
+        .strip_suffix('"')
+        // A quote preceded by an odd number of backslashes is escaped, so
+        // it doesn't close the literal.
+        .filter(|body| (body.len() - body.trim_end_matches('\\').len()) % 2 == 0)
+        .ok_or_else(|| missing_quote(QuotePosition::End))?;
+    safe_unescape_json_string(body).map_err(|err| UnescapeError {
+        span: err.span.start + 1..err.span.end + 1,
+        ..err
+    })
+}
+
+// Escapes [src] for display, as escape_json_string does, except that tabs
+// and newlines are shown according to [whitespace]. Unlike escape_json_string,
+// the result isn't necessarily a valid JSON string.
+#[allow(dead_code)]
+pub fn escape_for_display(src: &str, whitespace: WhitespaceRendering) -> String {
+    let mut escaped = String::with_capacity(src.len());
+    // Everything else is escaped as ASCII, so columns are just bytes.
+    let mut line_start = 0;
+    let mut rest = src;
+    while let Some(c) = rest.chars().next() {
+        if whitespace == WhitespaceRendering::Preserve {
+            let run_len = rest
+                .find(|c: char| c != ' ' && c != '\t')
+                .unwrap_or(rest.len());
+            if run_len > 0 {
+                escaped.push_str(&rest[..run_len]);
+                rest = &rest[run_len..];
+                continue;
+            }
+        }
+
+        rest = &rest[c.len_utf8()..];
+        match (c, whitespace) {
+            ('\t', WhitespaceRendering::Expand { tab_width }) => {
+                let tab_width = tab_width.max(1);
+                let col = escaped.len() - line_start;
+                let spaces = tab_width - col % tab_width;
+                escaped.extend(std::iter::repeat(' ').take(spaces));
+            }
+            ('\n', WhitespaceRendering::Expand { .. }) => {
+                escaped.push('\n');
+                line_start = escaped.len();
+            }
+            ('\t', WhitespaceRendering::Symbol) => escaped.push('→'),
+            ('\n', WhitespaceRendering::Symbol) => escaped.push('↵'),
+            (c, _) => escaped.push_str(escape_char(c, EscapeStyle::Json).as_str()),
         }
     }
     escaped
 }
 
-pub fn find_range_from_escaped(
-        unescaped: &str, escaped_range: Range<usize>) ->
-        (Range<usize>, Range<usize>) {
-    let mut start_escaped = 0;
-    let mut start_unescaped = 0;
-    let mut char_iter = unescaped.chars().peekable();
-    loop {
-        let len_unescaped;
-        match char_iter.peek() {
-            Some(v) => { len_unescaped = v.len_utf8(); }
-            None => { break; }
-        }
-        let len_escaped = escape_json_string(&unescaped[
-            start_unescaped..start_unescaped + len_unescaped]).len();
-        let next_pos = start_escaped + len_escaped;
-        if next_pos > escaped_range.start { break };
-        char_iter.next();
-        start_escaped += len_escaped;
-        start_unescaped += len_unescaped;
+// Replaces each C0 control character (U+0000 to U+001F) in [s] with its
+// symbol from the Control Pictures block (U+2400 to U+241F), e.g., '␀' for
+// NUL and '␉' for tab, and DEL with '␡' (U+2421), for a readable display
+// form of a raw string. C1 control characters (U+0080 to U+009F) have no
+// pictures, so they're written as "\u00XX" escapes, since they may still be
+// interpreted by the terminal. Everything else is left as is.
+#[allow(dead_code)]
+pub fn to_control_pictures(s: &str) -> String {
+    let mut pictures = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c as u32 {
+            codepoint @ 0x00..=0x1F => pictures.push(char::from_u32(0x2400 + codepoint).unwrap()),
+            0x7F => pictures.push('\u{2421}'),
+            codepoint @ 0x80..=0x9F => write!(pictures, "\\u{codepoint:04x}").unwrap(),
+            _ => pictures.push(c),
+        }
     }
-    let mut end_escaped = start_escaped;
-    let mut end_unescaped = start_unescaped;
-    loop {
-        let len_unescaped;
-        match char_iter.peek() {
-            Some(v) => { len_unescaped = v.len_utf8(); }
-            None => { break; }
+    pictures
+}
+
+// Splits [src] (an unescaped string) into lines, and escapes each one as
+// escape_json_string does, so that a multi-line value can be shown with
+// each line on its own row. The line breaks themselves aren't included.
+// Both "\n" and "\r\n" are treated as line breaks; a lone '\r' is not, and
+// is escaped. A trailing line break results in a final empty line.
+#[allow(dead_code)]
+pub fn escape_lines(src: &str) -> Vec<String> {
+    src.split('\n')
+        .map(|line| escape_json_string(line.strip_suffix('\r').unwrap_or(line)))
+        .collect()
+}
+
+// Escapes [src] for embedding in HTML text or attribute values, writing
+// characters that are special in HTML ('<', '>', '&', '"' and '\''),
+// control characters and non-ASCII characters as numeric character
+// references, e.g., "&#x3c;". Characters outside the Basic Multilingual
+// Plane are written as a single reference, not a surrogate pair.
+#[allow(dead_code)]
+pub fn escape_html_numeric(src: &str) -> String {
+    let mut escaped = String::with_capacity(src.len());
+    for c in src.chars() {
+        match c {
+            '<' | '>' | '&' | '"' | '\'' => write!(escaped, "&#x{:x};", c as u32).unwrap(),
+            ' ' => escaped.push(' '),
+            c if c.is_ascii_graphic() => escaped.push(c),
+            c => write!(escaped, "&#x{:x};", c as u32).unwrap(),
         }
-        let len_escaped = escape_json_string(&unescaped[
-            end_unescaped..end_unescaped + len_unescaped]).len();
-        end_escaped += len_escaped;
-        end_unescaped += len_unescaped;
-        char_iter.next();
-        let next_pos = end_escaped + len_escaped;
-        if next_pos >= escaped_range.end { break };
     }
-    return (
-        Range{start: start_unescaped, end: end_unescaped},
-        Range{start: start_escaped, end: end_escaped});
+    escaped
 }
 
-fn is_control(ch: char) -> bool {
-    matches!(ch as u32, 0x00..=0x1F | 0x7F..=0x9F)
+// Converts an escaped string from one escape style to another, e.g., turning
+// "\uD83D\uDE00" (JSON) into "\u{1f600}" (JSON5).
+#[allow(dead_code)]
+pub fn transcode_escape(
+    src_escaped: &str,
+    from: EscapeStyle,
+    to: EscapeStyle,
+) -> Result<String, UnescapeError> {
+    let config = UnescapeConfig {
+        escape_control_characters: false,
+        style: from,
+        ..UnescapeConfig::default()
+    };
+    let unescaped = unescape_json_string(src_escaped, &config)?;
+    Ok(escape_with_style(&unescaped, to))
 }
 
-// Consumes four hex characters from a Chars iterator, and converts it to a u16.
-// Also returns the four original characters as a mini [u8] that can be safely
-// interpreted as a str.
-fn parse_codepoint_from_chars(chars: &mut std::str::Chars<'_>) -> (u16, [u8; 4]) {
-    let mut codepoint = 0;
-    let chars = [
-        chars.next().unwrap(),
-        chars.next().unwrap(),
-        chars.next().unwrap(),
-        chars.next().unwrap(),
-    ];
-    let utf8_chars = [
-        chars[0] as u8,
-        chars[1] as u8,
-        chars[2] as u8,
-        chars[3] as u8,
-    ];
-    codepoint += hex_char_to_int(chars[0]) * 0x1000;
-    codepoint += hex_char_to_int(chars[1]) * 0x0100;
-    codepoint += hex_char_to_int(chars[2]) * 0x0010;
-    codepoint += hex_char_to_int(chars[3]);
-    (codepoint, utf8_chars)
+// Rewrites an escaped string, parsed according to [style], into its
+// canonical form (see is_canonically_escaped), writing every char exactly as
+// [style] writes it. Escapes that aren't needed (e.g., "\u0041" or "\/")
+// become literal chars, while chars that [style] escapes (e.g., 'é' with
+// EscapeStyle::Json) become escapes.
+#[allow(dead_code)]
+pub fn canonicalize_escapes(s: &str, style: EscapeStyle) -> Result<String, UnescapeError> {
+    let config = UnescapeConfig {
+        escape_control_characters: false,
+        style,
+        ..UnescapeConfig::default()
+    };
+    let unescaped = unescape_json_string(s, &config)?;
+    Ok(escape_with_style(&unescaped, style))
 }
 
-fn hex_char_to_int(ch: char) -> u16 {
-    match ch {
-        '0'..='9' => (ch as u16) - ('0' as u16),
-        'a'..='f' => (ch as u16) - ('a' as u16) + 10,
-        'A'..='f' => (ch as u16) - ('A' as u16) + 10,
-        _ => panic!("Unexpected non-hex digit: {}", ch),
+// How prepare_query normalizes a search query.
+#[derive(Debug, Default, Copy, Clone)]
+#[allow(dead_code)]
+pub struct QueryConfig {
+    // Convert the query to lowercase, for case insensitive matching against
+    // similarly lowercased text.
+    pub lowercase: bool,
+    // Convert the query to Unicode Normalization Form C, so that, e.g., an
+    // 'e' followed by a combining acute accent matches a precomposed 'é'.
+    pub nfc: bool,
+}
+
+// Validates a search query written as an escaped string (e.g., "caf\u00e9"),
+// normalizes it according to [config], and escapes it again as
+// escape_json_string would, so it can be matched against escaped text, and
+// its matches mapped back with find_range_from_escaped.
+#[allow(dead_code)]
+pub fn prepare_query(query: &str, config: &QueryConfig) -> Result<String, UnescapeError> {
+    let unescape_config = UnescapeConfig {
+        escape_control_characters: false,
+        ..UnescapeConfig::default()
+    };
+    let mut unescaped = unescape_json_string(query, &unescape_config)?;
+    if config.nfc {
+        unescaped = unescaped.nfc().collect();
+    }
+    if config.lowercase {
+        unescaped = unescaped.to_lowercase();
     }
+    Ok(escape_json_string(&unescaped))
 }
 
-// Interprets a codepoint in the Basic Multilingual Plane as either an actual
-// char, or one of a surrogate pair. The value associated with the surrogate
+#[derive(Debug, Default, Clone)]
+#[allow(dead_code)]
+pub struct EscapeConfig {
+    pub style: EscapeStyle,
+    // The maximum length of the escaped output, in bytes. Escaping can
+    // expand the input up to 12x (a 4-byte astral char becomes a 12-byte
+    // surrogate pair escape), so this guards against hostile input.
+    pub max_output_len: Option<usize>,
+    // If true, output that would exceed [max_output_len] is truncated
+    // (before the first char whose escape doesn't fit) instead of
+    // returning an error.
+    pub truncate_at_limit: bool,
+    pub hex_case: HexCase,
+    // If true, bidirectional control characters (see [is_bidi_control]) are
+    // always written as "\uXXXX" escapes, even with EscapeStyle::Minimal.
+    pub escape_bidi_controls: bool,
+    // If true, zero-width characters (see [is_zero_width]) are always
+    // written as "\uXXXX" escapes, even with EscapeStyle::Minimal.
+    pub escape_zero_width: bool,
+    // If true, the text and emoji presentation selectors (U+FE0E and
+    // U+FE0F) are written as "\uXXXX" escapes, even with
+    // EscapeStyle::Minimal. Terminals disagree on how wide an emoji followed
+    // by one of them is, so they can throw off the layout. The emoji itself
+    // is written as usual.
+    pub escape_variation_selectors: bool,
+    // If true, DEL (U+007F) is written as is, rather than as "\u007f". It's
+    // not a control character as far as JSON is concerned, so it doesn't
+    // need to be escaped, but terminals don't display it.
+    pub pass_through_del: bool,
+    // If true, combining marks without a base char to attach to (at the
+    // start of the string, or after whitespace, a control character or an
+    // escape) are written as "\uXXXX" escapes, so they don't appear to
+    // modify whatever is displayed before the string. Combining marks that
+    // follow a literal base char are written as usual.
+    pub escape_isolated_combining_marks: bool,
+    // Control characters with an entry in this table are written as its
+    // short escape. Others are escaped as [style] usually does.
+    pub short_escapes: ShortEscapeTable,
+    // If true, every escape is written as "\uXXXX", e.g., "\u000a" instead
+    // of "\n", "\u0022" instead of "\"", and "\u0001" instead of "\x01" with
+    // EscapeStyle::CHex, so the output only has one kind of escape to parse.
+    // This overrides [short_escapes]. It's the opposite of
+    // UnescapeConfig::prefer_short_escapes.
+    pub uniform_unicode_escapes: bool,
+    // If true, spaces at the start or end of the string are written as
+    // "\u0020", so that they're visible, while spaces between other chars
+    // are written as usual. Tabs are always escaped anyway.
+    pub escape_edge_whitespace: bool,
+    // If true, every char that isn't printable per its Unicode general
+    // category (see [is_non_printable]) is written as a "\uXXXX" escape,
+    // even with EscapeStyle::Minimal. This catches format characters,
+    // unassigned code points and private use characters, which is_control
+    // doesn't cover.
+    #[cfg(feature = "unicode-general-category")]
+    pub escape_non_printable: bool,
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct EscapeError {
+    // Byte offset in the source string of the first char that didn't fit.
+    index: usize,
+    limit: usize,
+}
+
+impl EscapeError {
+    // The byte offset in the source string of the first char whose escaped
+    // form didn't fit within the limit.
+    #[allow(dead_code)]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    // The limit on the length of the escaped output, in bytes, that was
+    // exceeded.
+    #[allow(dead_code)]
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+impl fmt::Display for EscapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "escaped output exceeds {} bytes at byte {} of input",
+            self.limit, self.index
+        )
+    }
+}
+
+// Like [escape_json_string], but respecting the limits in [config].
+#[allow(dead_code)]
+pub fn escape_json_string_with(src: &str, config: &EscapeConfig) -> Result<String, EscapeError> {
+    let limit = config.max_output_len.unwrap_or(usize::MAX);
+
+    let mut escaped = String::with_capacity(src.len().min(limit));
+    let mut utf16_buf = [0u16; 2];
+    // Whether the last char written is one a combining mark can attach to.
+    let mut has_base = false;
+    // Leading and trailing whitespace is outside of this range.
+    let interior = (src.len() - src.trim_start_matches([' ', '\t']).len())
+        ..src.trim_end_matches([' ', '\t']).len();
+    for (index, c) in src.char_indices() {
+        let prev_len = escaped.len();
+        let isolated_mark = !has_base && is_combining_mark(c);
+        if (config.escape_bidi_controls && is_bidi_control(c))
+            || (config.escape_zero_width && is_zero_width(c))
+            || (config.escape_variation_selectors && matches!(c, '\u{FE0E}' | '\u{FE0F}'))
+            || (config.escape_isolated_combining_marks && isolated_mark)
+            || (config.uniform_unicode_escapes && ((c as u32) < 0x20 || matches!(c, '"' | '\\')))
+            || (config.escape_edge_whitespace && c == ' ' && !interior.contains(&index))
+            || escape_non_printable(config, c)
+        {
+            escape_json_unicode(&mut escaped, c, "\\", config.hex_case, &mut utf16_buf).unwrap();
+        } else if config.pass_through_del && c == '\x7f' {
+            escaped.push(c);
+        } else if let Some(letter) = config.short_escapes.encode(c).filter(|_| is_control(c)) {
+            escaped.push('\\');
+            escaped.push(letter);
+        } else {
+            escape_json_char(&mut escaped, c, config.style, config.hex_case, &mut utf16_buf)
+                .unwrap();
+        }
+        if escaped.len() > limit {
+            if config.truncate_at_limit {
+                escaped.truncate(prev_len);
+                break;
+            }
+            return Err(EscapeError { index, limit });
+        }
+        has_base = !c.is_whitespace() && !is_control(c) && escaped[prev_len..].starts_with(c);
+    }
+    Ok(escaped)
+}
+
+#[cfg(feature = "unicode-general-category")]
+fn escape_non_printable(config: &EscapeConfig, c: char) -> bool {
+    config.escape_non_printable && is_non_printable(c)
+}
+
+#[cfg(not(feature = "unicode-general-category"))]
+fn escape_non_printable(_config: &EscapeConfig, _c: char) -> bool {
+    false
+}
+
+// An io::Write adapter that escapes everything written to it, as
+// escape_json_string does, before writing it to [inner]. The bytes written
+// must be valid UTF-8, but a char may be split across multiple writes.
+#[allow(dead_code)]
+pub struct EscapeWriter<W: io::Write> {
+    inner: W,
+    // The start of a UTF-8 sequence that was split across writes.
+    pending: [u8; 4],
+    pending_len: usize,
+}
+
+impl<W: io::Write> EscapeWriter<W> {
+    #[allow(dead_code)]
+    pub fn new(inner: W) -> EscapeWriter<W> {
+        EscapeWriter {
+            inner,
+            pending: [0; 4],
+            pending_len: 0,
+        }
+    }
+
+    // Returns the inner writer, or an error if the bytes written so far end
+    // in the middle of a char.
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> io::Result<W> {
+        if self.pending_len > 0 {
+            return Err(invalid_utf8_error());
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: io::Write> io::Write for EscapeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut escaped = String::new();
+        let mut rest = buf;
+
+        // Finish any char left over from the previous write.
+        while self.pending_len > 0 && !rest.is_empty() {
+            self.pending[self.pending_len] = rest[0];
+            self.pending_len += 1;
+            rest = &rest[1..];
+
+            match std::str::from_utf8(&self.pending[..self.pending_len]) {
+                Ok(c) => {
+                    escaped.push_str(&escape_json_string(c));
+                    self.pending_len = 0;
+                }
+                Err(err) if err.error_len().is_none() => {}
+                Err(_) => return Err(invalid_utf8_error()),
+            }
+        }
+
+        let valid = match std::str::from_utf8(rest) {
+            Ok(valid) => valid,
+            Err(err) => {
+                if err.error_len().is_some() {
+                    return Err(invalid_utf8_error());
+                }
+                // The buffer ends in the middle of a char.
+                let (valid, partial) = rest.split_at(err.valid_up_to());
+                self.pending[..partial.len()].copy_from_slice(partial);
+                self.pending_len = partial.len();
+                std::str::from_utf8(valid).unwrap()
+            }
+        };
+        escaped.push_str(&escape_json_string(valid));
+
+        self.inner.write_all(escaped.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// An io::Write adapter that unescapes everything written to it, as
+// unescape_json_string does, before writing it to [inner]. Chars, escape
+// sequences and surrogate pairs may be split across multiple writes; they're
+// held back until they're complete. Call [finish] once everything has been
+// written, to flush anything still held back.
+#[allow(dead_code)]
+pub struct UnescapeWriter<W: io::Write> {
+    inner: W,
+    config: UnescapeConfig,
+    // Input that hasn't been unescaped yet, because it ends in the middle of
+    // a char or escape sequence.
+    pending: Vec<u8>,
+    // The display column the output written to [inner] so far ends at.
+    column: usize,
+}
+
+impl<W: io::Write> UnescapeWriter<W> {
+    #[allow(dead_code)]
+    pub fn new(inner: W, config: &UnescapeConfig) -> UnescapeWriter<W> {
+        UnescapeWriter {
+            inner,
+            config: config.clone(),
+            pending: Vec::new(),
+            column: 0,
+        }
+    }
+
+    // The display column at which the next char written to [inner] will be
+    // printed, i.e., the width of the output since the last newline, so a
+    // renderer consuming the output doesn't have to measure it again. Input
+    // that's still held back isn't counted yet.
+    #[allow(dead_code)]
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    // Unescapes and writes anything still held back, and returns the inner
+    // writer. Fails if the input ended in the middle of an escape sequence.
+    #[allow(dead_code)]
+    pub fn finish(mut self) -> io::Result<W> {
+        let pending = std::mem::take(&mut self.pending);
+        let escaped = std::str::from_utf8(&pending).map_err(|_| invalid_utf8_error())?;
+        self.unescape_and_write(escaped)?;
+        Ok(self.inner)
+    }
+
+    fn unescape_and_write(&mut self, escaped: &str) -> io::Result<()> {
+        let unescaped = unescape_json_string(escaped, &self.config)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        // A byte order mark is only special at the very start.
+        if !unescaped.is_empty() {
+            self.config.bom_policy = BomPolicy::Keep;
+        }
+        let last_line = match unescaped.rfind('\n') {
+            Some(newline) => {
+                self.column = 0;
+                &unescaped[newline + 1..]
+            }
+            None => &unescaped[..],
+        };
+        self.column += UnicodeWidthStr::width(last_line);
+        self.inner.write_all(unescaped.as_bytes())
+    }
+}
+
+impl<W: io::Write> io::Write for UnescapeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+
+        let valid_len = match std::str::from_utf8(&self.pending) {
+            Ok(valid) => valid.len(),
+            Err(err) if err.error_len().is_none() => err.valid_up_to(),
+            Err(_) => return Err(invalid_utf8_error()),
+        };
+        let pending = std::mem::take(&mut self.pending);
+        let valid = std::str::from_utf8(&pending[..valid_len]).unwrap();
+        let complete_len = complete_prefix_len(valid, self.config.style);
+
+        let result = self.unescape_and_write(&valid[..complete_len]);
+        self.pending = pending;
+        self.pending.drain(..complete_len);
+        result.map(|()| buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Reads escaped strings from [input], one per line, and writes each one to
+// [out] unescaped according to [config], followed by the line ending it had
+// ("\n" or "\r\n"), if any. Since escape sequences can't contain raw line
+// breaks, no escape (or surrogate pair) spans multiple lines. Fails on the
+// first line that can't be unescaped, with an InvalidData error.
+#[allow(dead_code)]
+pub fn unescape_lines<R: io::BufRead, W: io::Write>(
+    mut input: R,
+    mut out: W,
+    config: &UnescapeConfig,
+) -> io::Result<()> {
+    let mut line = String::new();
+    for line_number in 1.. {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+
+        let content = line.trim_end_matches('\n');
+        let content = content.strip_suffix('\r').unwrap_or(content);
+        let invalid_line = |message: &dyn fmt::Display| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {line_number}: {message}"),
+            )
+        };
+        let unescaped = unescape_json_string(content, config).map_err(|err| invalid_line(&err))?;
+        out.write_all(unescaped.as_bytes())?;
+        out.write_all(&line.as_bytes()[content.len()..])?;
+    }
+    Ok(())
+}
+
+// Returns the length of the longest prefix of [escaped] that doesn't end in
+// the middle of an escape sequence, i.e., in an escape sequence that might
+// still be completed by more input. A high surrogate escape at the end is
+// considered incomplete, since it might be followed by a low surrogate.
+// Escapes that are already invalid are considered complete, so that they're
+// reported as errors.
+fn complete_prefix_len(escaped: &str, style: EscapeStyle) -> usize {
+    // Whether [bytes] could be the start of a "\uXXXX" escape.
+    fn is_unicode_escape_prefix(bytes: &[u8]) -> bool {
+        bytes.len() < 6
+            && b"\\u".starts_with(&bytes[..bytes.len().min(2)])
+            && bytes.iter().skip(2).all(u8::is_ascii_hexdigit)
+    }
+
+    let bytes = escaped.as_bytes();
+    let mut offset = 0;
+    while let Some(i) = bytes[offset..].iter().position(|&b| b == b'\\') {
+        let start = offset + i;
+        let rest = &bytes[start..];
+        let len = match rest.get(1) {
+            None => return start,
+            Some(b'u') if style == EscapeStyle::Json5 && rest.get(2) == Some(&b'{') => {
+                // Only incomplete while it's still a valid "\u{" and up to
+                // six hex digits. Otherwise it's already invalid.
+                let num_digits = rest[3..].iter().take_while(|b| b.is_ascii_hexdigit()).count();
+                if 3 + num_digits == rest.len() && num_digits <= 6 {
+                    return start;
+                }
+                (3 + num_digits + 1).min(rest.len())
+            }
+            Some(b'u') if rest.len() < 6 => {
+                if is_unicode_escape_prefix(rest) {
+                    return start;
+                }
+                2
+            }
+            Some(b'u') => match hex4_at(rest, 2) {
+                Some(0xD800..=0xDBFF) if is_unicode_escape_prefix(&rest[6..]) => return start,
+                _ => 6,
+            },
+            Some(b'x') if matches!(style, EscapeStyle::CHex | EscapeStyle::Json5) => {
+                if rest.len() < 4 {
+                    return start;
+                }
+                4
+            }
+            Some(_) => 2,
+        };
+        offset = start + len;
+    }
+    bytes.len()
+}
+
+fn invalid_utf8_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8")
+}
+
+// Source: https://www.reddit.com/r/rust/comments/i4bg0q/comment/g0hl58g/?utm_source=share&utm_medium=web2x&context=3
+fn escape_json_char<W: Write>(
+        escaped: &mut W, c: char, style: EscapeStyle,
+        hex_case: HexCase, utf16_buf: &mut [u16]) -> fmt::Result {
+    match c {
+        '\x08' => escaped.write_str("\\b"),
+        '\x0c' => escaped.write_str("\\f"),
+        '\n' => escaped.write_str("\\n"),
+        '\r' => escaped.write_str("\\r"),
+        '\t' => escaped.write_str("\\t"),
+        '"' => escaped.write_str("\\\""),
+        '\\' => escaped.write_str("\\\\"),
+        ' ' => escaped.write_str(" "),
+        c if c.is_ascii_graphic() => escaped.write_char(c),
+        // DEL isn't an ASCII graphic char, and is always escaped as "\u007f",
+        // even by EscapeStyle::CHex, which only uses "\xHH" for C0 controls.
+        '\x7f' => escape_json_unicode(escaped, c, "\\", hex_case, utf16_buf),
+        c if style == EscapeStyle::CHex && (c as u32) < 0x20 => match hex_case {
+            HexCase::Lower => write!(escaped, "\\x{:02x}", c as u32),
+            HexCase::Upper => write!(escaped, "\\x{:02X}", c as u32),
+        },
+        c if style == EscapeStyle::Json5 && (c as u32) > 0xFFFF => match hex_case {
+            HexCase::Lower => write!(escaped, "\\u{{{:x}}}", c as u32),
+            HexCase::Upper => write!(escaped, "\\u{{{:X}}}", c as u32),
+        },
+        c if style == EscapeStyle::Minimal && !is_control(c) => escaped.write_char(c),
+        c => escape_json_unicode(escaped, c, "\\", hex_case, utf16_buf),
+    }
+}
+
+// Returns the escaped form of [c], as escape_json_string would write it
+// with [style], without allocating.
+#[allow(dead_code)]
+pub fn escape_char(c: char, style: EscapeStyle) -> EscapedChar {
+    EscapedChar::new(c, style)
+}
+
+// Returns the escaped form of the code point [codepoint], as
+// escape_json_string would write it with [style], e.g., "\n" for 0x0A, or
+// "\ud801\udc37" for 0x10437 (or "\u{10437}" with EscapeStyle::Json5).
+// Returns None if [codepoint] isn't a valid char, e.g., if it's a
+// surrogate.
+#[allow(dead_code)]
+pub fn codepoint_to_json_escape(codepoint: u32, style: EscapeStyle) -> Option<String> {
+    char::from_u32(codepoint).map(|c| escape_char(c, style).to_string())
+}
+
+// Returns the length in bytes of the escaped form of [c].
+pub fn escaped_len_of_char(c: char, style: EscapeStyle) -> usize {
+    EscapedChar::new(c, style).len
+}
+
+// Returns the exact length in bytes of escaping [src] with [style], e.g.,
+// escape_json_string(src).len() for EscapeStyle::Json, without building
+// the escaped string.
+#[allow(dead_code)]
+pub fn escaped_len_exact(src: &str, style: EscapeStyle) -> usize {
+    src.chars().map(|c| escaped_len_of_char(c, style)).sum()
+}
+
+// Returns the length in bytes of the escaped form of each char of
+// [unescaped], in order. Their prefix sums are the offsets at which each
+// char's escaped form starts, as in char_boundaries.
+#[allow(dead_code)]
+pub fn escaped_byte_lengths(unescaped: &str, style: EscapeStyle) -> Vec<usize> {
+    unescaped
+        .chars()
+        .map(|c| escaped_len_of_char(c, style))
+        .collect()
+}
+
+// Returns the total length in bytes of escaping each of [inputs] with
+// [style], e.g., to decide whether a large collection of values should be
+// escaped in a streaming fashion instead of all at once.
+#[allow(dead_code)]
+pub fn total_escaped_len(inputs: &[&str], style: EscapeStyle) -> usize {
+    inputs.iter().map(|src| escaped_len_exact(src, style)).sum()
+}
+
+// Escapes each of [parts] with [style], and joins them with [sep], which is
+// copied as is, not escaped. The output is allocated once, at its exact
+// size, and the parts are escaped directly into it.
+#[allow(dead_code)]
+pub fn escape_join(parts: &[&str], sep: &str, style: EscapeStyle) -> String {
+    let sep_len = sep.len() * parts.len().saturating_sub(1);
+    let mut joined = String::with_capacity(total_escaped_len(parts, style) + sep_len);
+    let mut utf16_buf = [0u16; 2];
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            joined.push_str(sep);
+        }
+        for c in part.chars() {
+            escape_json_char(&mut joined, c, style, HexCase::Lower, &mut utf16_buf).unwrap();
+        }
+    }
+    joined
+}
+
+// Returns how many leading chars of [src] can be escaped with [style]
+// without the escaped output exceeding [max_bytes], e.g., to split [src]
+// into chunks without splitting any char's escape.
+#[allow(dead_code)]
+pub fn chars_fitting_in_bytes(src: &str, max_bytes: usize, style: EscapeStyle) -> usize {
+    let mut total_len = 0;
+    src.chars()
+        .take_while(|&c| {
+            total_len += escaped_len_of_char(c, style);
+            total_len <= max_bytes
+        })
+        .count()
+}
+
+// The escaped form of a single char, stored inline. No char's escaped form
+// is longer than a 12 byte surrogate pair escape.
+#[derive(Clone, Copy)]
+pub struct EscapedChar {
+    buf: [u8; 12],
+    len: usize,
+}
+
+impl EscapedChar {
+    fn new(c: char, style: EscapeStyle) -> EscapedChar {
+        let mut escaped = EscapedChar { buf: [0; 12], len: 0 };
+        escape_json_char(&mut escaped, c, style, HexCase::Lower, &mut [0u16; 2]).unwrap();
+        escaped
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole strs are ever written to the buffer.
+        std::str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+}
+
+impl fmt::Debug for EscapedChar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for EscapedChar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Write for EscapedChar {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+// Returns whether [s] is already exactly what re-escaping its unescaped value
+// with [style] would produce, i.e., whether
+//
+//   escape(unescape(s)) == s
+//
+// but without building either intermediate string.
+#[allow(dead_code)]
+pub fn is_canonically_escaped(s: &str, style: EscapeStyle) -> bool {
+    first_noncanonical(s, style).is_none()
+}
+
+// Returns the byte offset of the first char in [s] that isn't escaped the
+// way re-escaping it with [style] would (e.g., "\u0041" rather than "A", or
+// uppercase hex digits), or that's an invalid escape. Returns None if [s] is
+// canonically escaped (see is_canonically_escaped).
+#[allow(dead_code)]
+pub fn first_noncanonical(s: &str, style: EscapeStyle) -> Option<usize> {
+    let config = canonical_config(style);
+    for decoded in UnescapeIter::new(s, &config) {
+        let decoded = match decoded {
+            Ok(decoded) => decoded,
+            Err(err) => return Some(err.span.start),
+        };
+        if EscapedChar::new(decoded.ch, style).as_str() != &s[decoded.source.clone()] {
+            return Some(decoded.source.start);
+        }
+    }
+    None
+}
+
+// Decodes the first logical char of an escaped string, returning the char
+// and the number of bytes it occupied. Returns None if the string is empty
+// or starts with an invalid or unpaired escape sequence.
+pub fn decode_escaped_char(s: &str, style: EscapeStyle) -> Option<(char, usize)> {
+    let config = canonical_config(style);
+    let decoded = UnescapeIter::new(s, &config).next()?.ok()?;
+    Some((decoded.ch, decoded.source.end))
+}
+
+// The config for decoding escapes exactly as [style] defines them, with no
+// lenient or strict extras, for comparing against EscapedChar.
+fn canonical_config(style: EscapeStyle) -> UnescapeConfig {
+    UnescapeConfig {
+        escape_control_characters: false,
+        style,
+        ..UnescapeConfig::default()
+    }
+}
+
+// Escapes every char for which [allowed] returns false as "\uXXXX", passing
+// the rest through unchanged. Quotes and backslashes are always escaped (as
+// "\"" and "\\"), so the output is still a valid JSON string as long as
+// [allowed] rejects control characters.
+#[allow(dead_code)]
+pub fn escape_with_allowed(src: &str, allowed: &dyn Fn(char) -> bool) -> String {
+    let mut escaped = String::with_capacity(src.len());
+    let mut utf16_buf = [0u16; 2];
+    for c in src.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if allowed(c) => escaped.push(c),
+            c => escape_json_unicode(&mut escaped, c, "\\", HexCase::Lower, &mut utf16_buf)
+                .unwrap(),
+        }
+    }
+    escaped
+}
+
+// Whether [c] is copied as is by escape_with_encoder: printable ASCII chars
+// that aren't special in JSON, HTML or common string literal syntaxes.
+fn is_encoder_safe(c: char) -> bool {
+    c == ' ' || (c.is_ascii_graphic() && !matches!(c, '"' | '\\' | '\'' | '<' | '>' | '&'))
+}
+
+// Escapes [src] using [encode_special] to render every char outside a safe
+// subset of printable ASCII. Quotes, '\', '<', '>', '&', control characters
+// and non-ASCII chars are all passed to [encode_special], which appends
+// their encoded form to the output. Runs of safe chars are copied in bulk.
+#[allow(dead_code)]
+pub fn escape_with_encoder<F: FnMut(&mut String, char)>(
+    src: &str,
+    mut encode_special: F,
+) -> String {
+    let mut escaped = String::with_capacity(src.len());
+    let mut rest = src;
+    while !rest.is_empty() {
+        let safe_len = rest
+            .find(|c: char| !is_encoder_safe(c))
+            .unwrap_or(rest.len());
+        escaped.push_str(&rest[..safe_len]);
+        rest = &rest[safe_len..];
+
+        if let Some(c) = rest.chars().next() {
+            encode_special(&mut escaped, c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    escaped
+}
+
+// Escapes the chars of [src] that the terminal can't display, according to
+// [can_display], as "\uXXXX", and passes the rest through. Quotes,
+// backslashes and control characters are always escaped, as
+// EscapeStyle::Minimal escapes them, so the output is a valid JSON string.
+#[allow(dead_code)]
+pub fn escape_undisplayable(src: &str, can_display: &dyn Fn(char) -> bool) -> String {
+    let mut escaped = String::with_capacity(src.len());
+    let mut utf16_buf = [0u16; 2];
+    for c in src.chars() {
+        let result = if can_display(c) {
+            escape_json_char(&mut escaped, c, EscapeStyle::Minimal, HexCase::Lower, &mut utf16_buf)
+        } else {
+            escape_json_unicode(&mut escaped, c, "\\", HexCase::Lower, &mut utf16_buf)
+        };
+        result.unwrap();
+    }
+    escaped
+}
+
+// Escapes [src] for embedding in JavaScript source, e.g., inside a <script>
+// block, while passing through most non-ASCII text. On top of what
+// EscapeStyle::Minimal escapes, this escapes:
+//
+// - U+2028 and U+2029 (line and paragraph separator), which are line
+//   terminators in JavaScript string literals before ES2019
+// - Bidirectional control characters (see [is_bidi_control])
+// - '<', so that the string can't contain "</script>" or "<!--"
+#[allow(dead_code)]
+pub fn escape_js_safe(src: &str) -> String {
+    let mut escaped = String::with_capacity(src.len());
+    let mut utf16_buf = [0u16; 2];
+    for c in src.chars() {
+        let result = if matches!(c, '\u{2028}' | '\u{2029}' | '<') || is_bidi_control(c) {
+            escape_json_unicode(&mut escaped, c, "\\", HexCase::Lower, &mut utf16_buf)
+        } else {
+            escape_json_char(&mut escaped, c, EscapeStyle::Minimal, HexCase::Lower, &mut utf16_buf)
+        };
+        result.unwrap();
+    }
+    escaped
+}
+
+// Makes a best effort attempt to turn a malformed escaped string into one
+// that can be unescaped without errors. Valid escapes are kept as is, and
+// invalid ones are repaired as follows:
+//
+// - An unpaired surrogate escape (e.g., "\uD83D" not followed by a low
+//   surrogate) is replaced with U+FFFD, the replacement character.
+// - A "\u" not followed by four hex digits (e.g., a truncated "\u12" at
+//   the end of the string) loses its '\', leaving the rest as literal
+//   text ("u12").
+// - An unknown escape (e.g., "\z") also loses its '\', leaving a literal
+//   char ("z"). A trailing '\' is dropped.
+#[allow(dead_code)]
+pub fn repair_escapes(s: &str) -> String {
+    let mut repaired = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(backslash) = rest.find('\\') {
+        repaired.push_str(&rest[..backslash]);
+        rest = &rest[backslash..];
+
+        let bytes = rest.as_bytes();
+        let len = match decode_escaped_char(rest, EscapeStyle::Json) {
+            Some((_, len)) => {
+                repaired.push_str(&rest[..len]);
+                len
+            }
+            None => match hex4_at(bytes, 2) {
+                Some(0xD800..=0xDFFF) if bytes[1] == b'u' => {
+                    repaired.push('\u{FFFD}');
+                    6
+                }
+                _ => 1,
+            },
+        };
+        rest = &rest[len..];
+    }
+    repaired.push_str(rest);
+    repaired
+}
+
+// Like repair_escapes, but also returns whether the repaired string can now
+// be unescaped with safe_unescape_json_string, so callers don't have to
+// check it themselves.
+#[allow(dead_code)]
+pub fn repair_and_verify(s: &str) -> (String, bool) {
+    let repaired = repair_escapes(s);
+    let unescapes = safe_unescape_json_string(&repaired).is_ok();
+    (repaired, unescapes)
+}
+
+// Replaces non-ASCII characters with "\uXXXX" escapes. If [double_backslash]
+// is true, the escapes are written as "\\uXXXX", so that the resulting regex
+// matches a literal "\uXXXX" escape in JSON text. Otherwise, the escapes are
+// written with a single backslash, which a regex interprets as the char itself.
+//
+// Input that's entirely ASCII is returned as is, without allocating.
+pub fn escape_unicode_for_regex(src: &str, double_backslash: bool) -> Cow<'_, str> {
+    if src.is_ascii() {
+        return Cow::Borrowed(src);
+    }
+
+    let backslash = if double_backslash { "\\\\" } else { "\\" };
+    let mut escaped = String::with_capacity(src.len());
+    let mut utf16_buf = [0u16; 2];
+    for c in src.chars() {
+        match c {
+            c if c.is_ascii() => escaped.push(c),
+            c => escape_json_unicode(&mut escaped, c, backslash, HexCase::Lower, &mut utf16_buf)
+                .unwrap(),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+// Iterates over the chars of [src] along with their escaped forms, as
+// produced by escape_json_string. Each item is the byte range of the char in
+// [src], and its escaped form, which borrows from [src] if the char doesn't
+// need escaping.
+#[allow(dead_code)]
+pub fn escape_chunks(src: &str) -> EscapeChunks<'_> {
+    EscapeChunks {
+        src,
+        char_indices: src.char_indices(),
+    }
+}
+
+// Like [escape_chunks], but starting from the end of [src].
+#[allow(dead_code)]
+pub fn escape_chunks_rev(src: &str) -> impl Iterator<Item = (Range<usize>, Cow<'_, str>)> {
+    escape_chunks(src).rev()
+}
+
+#[allow(dead_code)]
+pub struct EscapeChunks<'a> {
+    src: &'a str,
+    char_indices: CharIndices<'a>,
+}
+
+impl<'a> EscapeChunks<'a> {
+    fn chunk(&self, index: usize, c: char) -> (Range<usize>, Cow<'a, str>) {
+        let range = index..index + c.len_utf8();
+        let escaped = EscapedChar::new(c, EscapeStyle::Json);
+        if escaped.as_str() == &self.src[range.clone()] {
+            (range.clone(), Cow::Borrowed(&self.src[range]))
+        } else {
+            (range, Cow::Owned(escaped.as_str().to_owned()))
+        }
+    }
+}
+
+impl<'a> Iterator for EscapeChunks<'a> {
+    type Item = (Range<usize>, Cow<'a, str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (index, c) = self.char_indices.next()?;
+        Some(self.chunk(index, c))
+    }
+}
+
+impl<'a> DoubleEndedIterator for EscapeChunks<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (index, c) = self.char_indices.next_back()?;
+        Some(self.chunk(index, c))
+    }
+}
+
+// Builds a regex that matches [query] literally, as it appears in escaped
+// JSON text. The query is escaped first, so that quotes, backslashes,
+// control characters and non-ASCII characters match their escape sequences,
+// and then any regex metacharacters (including the backslashes introduced
+// by escaping) are escaped.
+#[allow(dead_code)]
+pub fn build_literal_regex(query: &str) -> String {
+    regex::escape(&escape_json_string(query))
+}
+
+// Returns, for every char in [unescaped], the byte offset at which it starts,
+// and the byte offset at which its escaped form starts in
+// escape_json_string(unescaped). A final entry holds the total lengths of
+// both strings. Both offsets are strictly increasing, so the result can be
+// binary searched in either direction.
+#[allow(dead_code)]
+pub fn char_boundaries(unescaped: &str) -> Vec<(usize, usize)> {
+    let mut boundaries = Vec::with_capacity(unescaped.len() + 1);
+    let mut escaped_offset = 0;
+    for (offset, c) in unescaped.char_indices() {
+        boundaries.push((offset, escaped_offset));
+        escaped_offset += escaped_len_of_char(c, EscapeStyle::Json);
+    }
+    boundaries.push((unescaped.len(), escaped_offset));
+    boundaries
+}
+
+// A single char of an unescaped string, and where it ends up in the escaped
+// string.
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[allow(dead_code)]
+pub struct EditSpan {
+    pub unescaped: Range<usize>,
+    pub escaped: Range<usize>,
+    // Whether the char was escaped, rather than copied as is.
+    pub transformed: bool,
+}
+
+// Returns an EditSpan for every char in [unescaped], mapping it to its
+// escaped form in escape_json_string(unescaped). Like char_boundaries, but
+// also records which chars were escaped, e.g., for highlighting the
+// differences between the raw and escaped strings side by side.
+#[allow(dead_code)]
+pub fn edit_map(unescaped: &str) -> Vec<EditSpan> {
+    let mut spans = Vec::with_capacity(unescaped.len());
+    let mut escaped_offset = 0;
+    for (offset, c) in unescaped.char_indices() {
+        let escaped_char = escape_char(c, EscapeStyle::Json);
+        let escaped_len = escaped_char.as_str().len();
+        let unescaped_range = offset..offset + c.len_utf8();
+        spans.push(EditSpan {
+            transformed: escaped_char.as_str() != &unescaped[unescaped_range.clone()],
+            unescaped: unescaped_range,
+            escaped: escaped_offset..escaped_offset + escaped_len,
+        });
+        escaped_offset += escaped_len;
+    }
+    spans
+}
+
+// Returns the index of the char in [unescaped] whose escaped form contains
+// byte [escaped_byte] of escape_json_string(unescaped). Offsets past the end
+// return the number of chars in [unescaped].
+#[allow(dead_code)]
+pub fn char_index_of_escaped_byte(unescaped: &str, escaped_byte: usize) -> usize {
+    let boundaries = char_boundaries(unescaped);
+    boundaries.partition_point(|&(_, escaped_start)| escaped_start <= escaped_byte) - 1
+}
+
+// Returns the char in [unescaped] whose escaped form contains byte
+// [escaped_byte] of escape_json_string(unescaped), e.g., '𐐷' for any byte
+// of "\ud801\udc37". Offsets past the end return None.
+#[allow(dead_code)]
+pub fn char_at_escaped_byte(unescaped: &str, escaped_byte: usize) -> Option<char> {
+    let mut escaped_end = 0;
+    unescaped.chars().find(|&c| {
+        escaped_end += escaped_len_of_char(c, EscapeStyle::Json);
+        escaped_byte < escaped_end
+    })
+}
+
+// Given a range in escape_json_string(unescaped), returns the range of the
+// chars in [unescaped] whose escaped forms overlap it, along with the range
+// those chars occupy in the escaped string. If no chars overlap the range,
+// returns empty ranges at the first char boundary at or after its start.
+pub fn find_range_from_escaped(
+        unescaped: &str, escaped_range: Range<usize>) ->
+        (Range<usize>, Range<usize>) {
+    let mut char_lens = unescaped
+        .chars()
+        .map(|c| (c.len_utf8(), escaped_len_of_char(c, EscapeStyle::Json)))
+        .peekable();
+
+    let mut start_unescaped = 0;
+    let mut start_escaped = 0;
+    while let Some(&(len_unescaped, len_escaped)) = char_lens.peek() {
+        if start_escaped + len_escaped > escaped_range.start {
+            break;
+        }
+        char_lens.next();
+        start_unescaped += len_unescaped;
+        start_escaped += len_escaped;
+    }
+
+    let mut end_unescaped = start_unescaped;
+    let mut end_escaped = start_escaped;
+    while end_escaped < escaped_range.end {
+        match char_lens.next() {
+            Some((len_unescaped, len_escaped)) => {
+                end_unescaped += len_unescaped;
+                end_escaped += len_escaped;
+            }
+            None => break,
+        }
+    }
+
+    (start_unescaped..end_unescaped, start_escaped..end_escaped)
+}
+
+// An unescaped string's escaped form, along with the offsets of every char
+// in both, so ranges can be mapped between them in O(log n) time, rather
+// than with a linear scan like find_range_from_escaped does. Useful for
+// large strings that are searched repeatedly.
+#[allow(dead_code)]
+pub struct IndexedString {
+    escaped: String,
+    boundaries: Vec<(usize, usize)>,
+}
+
+impl IndexedString {
+    #[allow(dead_code)]
+    pub fn new(unescaped: &str) -> IndexedString {
+        IndexedString {
+            escaped: escape_json_string(unescaped),
+            boundaries: char_boundaries(unescaped),
+        }
+    }
+
+    // The escaped string, as escape_json_string would return it.
+    #[allow(dead_code)]
+    pub fn escaped(&self) -> &str {
+        &self.escaped
+    }
+
+    // Given a range in the unescaped string, returns the range of the chars
+    // that overlap it, and the range their escaped forms occupy, with the
+    // same rules as escaped_to_unescaped.
+    #[allow(dead_code)]
+    pub fn unescaped_to_escaped(
+        &self,
+        unescaped_range: Range<usize>,
+    ) -> (Range<usize>, Range<usize>) {
+        self.map_range(unescaped_range, |&(unescaped, _)| unescaped)
+    }
+
+    // Same as find_range_from_escaped(unescaped, escaped_range).
+    #[allow(dead_code)]
+    pub fn escaped_to_unescaped(
+        &self,
+        escaped_range: Range<usize>,
+    ) -> (Range<usize>, Range<usize>) {
+        self.map_range(escaped_range, |&(_, escaped)| escaped)
+    }
+
+    // Maps [range], using [offset] to pick which side of each boundary it's
+    // a range of.
+    fn map_range<F: Fn(&(usize, usize)) -> usize>(
+        &self,
+        range: Range<usize>,
+        offset: F,
+    ) -> (Range<usize>, Range<usize>) {
+        let num_chars = self.boundaries.len() - 1;
+        // The first char that ends after the start of the range...
+        let first = self.boundaries[1..].partition_point(|b| offset(b) <= range.start);
+        // ...through the last char that starts before its end.
+        let end = self.boundaries[..num_chars]
+            .partition_point(|b| offset(b) < range.end)
+            .max(first);
+        let (start, end) = (self.boundaries[first], self.boundaries[end]);
+        (start.0..end.0, start.1..end.1)
+    }
+}
+
+// Sorts a list of ranges, and merges any ranges that overlap or touch, so
+// that highlighting them doesn't require switching styles mid-match.
+#[allow(dead_code)]
+pub fn merge_ranges(ranges: &mut Vec<Range<usize>>) {
+    ranges.sort_by_key(|range| range.start);
+    ranges.dedup_by(|next, prev| {
+        if next.start <= prev.end {
+            prev.end = prev.end.max(next.end);
+            true
+        } else {
+            false
+        }
+    });
+}
+
+// Maps [unescaped_ranges], which must be sorted by their start, to the
+// ranges of escape_json_string(unescaped) that a highlighter should style,
+// in a single pass over [unescaped]. Each range covers the escaped forms of
+// all the chars it overlaps, and ranges that overlap or touch once escaped
+// are merged, as merge_ranges does. Empty ranges are ignored.
+#[allow(dead_code)]
+pub fn escaped_highlight_spans(
+    unescaped: &str,
+    unescaped_ranges: &[Range<usize>],
+) -> Vec<Range<usize>> {
+    let mut spans: Vec<Range<usize>> = vec![];
+    let mut ranges = unescaped_ranges
+        .iter()
+        .filter(|range| !range.is_empty())
+        .peekable();
+    let mut escaped_offset = 0;
+    for (offset, c) in unescaped.char_indices() {
+        while ranges.peek().map_or(false, |range| range.end <= offset) {
+            ranges.next();
+        }
+        let range = match ranges.peek() {
+            Some(range) => range,
+            None => break,
+        };
+
+        let escaped_end = escaped_offset + escaped_len_of_char(c, EscapeStyle::Json);
+        if range.start < offset + c.len_utf8() {
+            match spans.last_mut() {
+                Some(last) if last.end >= escaped_offset => last.end = escaped_end,
+                _ => spans.push(escaped_offset..escaped_end),
+            }
+        }
+        escaped_offset = escaped_end;
+    }
+    spans
+}
+
+// Returns the length in bytes of the escape-aware chunk at the start of an
+// escaped string. A chunk is either a complete escape sequence ("\n",
+// "\uXXXX", or a "\uXXXX\uXXXX" surrogate pair, which counts as a single
+// chunk), or a single literal char. Returns 0 for an empty string.
+pub fn escaped_chunk_len(escaped: &str) -> usize {
+    let bytes = escaped.as_bytes();
+    if bytes.first() != Some(&b'\\') {
+        return escaped.chars().next().map_or(0, char::len_utf8);
+    }
+
+    match bytes.get(1) {
+        None => 1,
+        Some(b'u') => match hex4_at(bytes, 2) {
+            Some(0xD800..=0xDBFF) => {
+                let low_surrogate_follows = bytes.get(6) == Some(&b'\\')
+                    && bytes.get(7) == Some(&b'u')
+                    && matches!(hex4_at(bytes, 8), Some(0xDC00..=0xDFFF));
+                if low_surrogate_follows {
+                    12
+                } else {
+                    6
+                }
+            }
+            Some(_) => 6,
+            None => 2,
+        },
+        Some(_) => 1 + escaped[1..].chars().next().unwrap().len_utf8(),
+    }
+}
+
+// Iterates over the byte ranges of the escape-aware chunks of an escaped
+// string; see [escaped_chunk_len].
+pub fn escaped_chunks(escaped: &str) -> EscapedChunks<'_> {
+    EscapedChunks { escaped, offset: 0 }
+}
+
+pub struct EscapedChunks<'a> {
+    escaped: &'a str,
+    offset: usize,
+}
+
+impl<'a> Iterator for EscapedChunks<'a> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Range<usize>> {
+        let len = escaped_chunk_len(&self.escaped[self.offset..]);
+        if len == 0 {
+            return None;
+        }
+        let start = self.offset;
+        self.offset += len;
+        Some(start..self.offset)
+    }
+}
+
+// Returns the longest prefix of [escaped] that's at most [max_bytes] long
+// and doesn't end in the middle of a char or an escape sequence (see
+// [escaped_chunks]).
+#[allow(dead_code)]
+pub fn truncate_to_bytes(escaped: &str, max_bytes: usize) -> &str {
+    let end = escaped_chunks(escaped)
+        .map(|chunk| chunk.end)
+        .take_while(|&end| end <= max_bytes)
+        .last()
+        .unwrap_or(0);
+    &escaped[..end]
+}
+
+// Splits [s], a sequence of double quoted JSON strings (e.g., concatenated
+// string literals, separated by whitespace or anything else), into the
+// escaped bodies of those strings. Text outside of quotes is skipped, and
+// an escaped quote ("\"") doesn't end a string. If the last string isn't
+// terminated, the rest of [s] is yielded as its body.
+#[allow(dead_code)]
+pub fn split_escaped_strings(s: &str) -> impl Iterator<Item = &str> {
+    let mut rest = s;
+    std::iter::from_fn(move || {
+        let open = rest.find('"')?;
+        let after_open = &rest[open + 1..];
+        let body_len = escaped_chunks(after_open)
+            .find(|chunk| &after_open[chunk.clone()] == "\"")
+            .map_or(after_open.len(), |chunk| chunk.start);
+        rest = after_open.get(body_len + 1..).unwrap_or("");
+        Some(&after_open[..body_len])
+    })
+}
+
+// Returns the byte range of the [n]th (0-based) escape sequence in [escaped],
+// e.g., for an editor stepping from escape to escape. Escapes are split as
+// by [escaped_chunks], so a surrogate pair counts as a single escape.
+#[allow(dead_code)]
+pub fn nth_escape_range(escaped: &str, n: usize) -> Option<Range<usize>> {
+    escaped_chunks(escaped)
+        .filter(|chunk| escaped[chunk.clone()].starts_with('\\'))
+        .nth(n)
+}
+
+// Returns the byte offset of the first occurrence of [needle_escaped] in
+// [haystack_escaped], comparing the escaped text directly. A match must
+// start and end on chunk boundaries of the haystack (see [escaped_chunks]),
+// so it can't begin or end in the middle of an escape sequence (e.g., "\n"
+// doesn't match the end of "\\n"). A needle that doesn't consist of whole
+// chunks therefore never matches.
+#[allow(dead_code)]
+pub fn find_in_escaped(haystack_escaped: &str, needle_escaped: &str) -> Option<usize> {
+    let boundaries =
+        || std::iter::once(0).chain(escaped_chunks(haystack_escaped).map(|chunk| chunk.end));
+    let mut end_boundaries = boundaries().peekable();
+
+    for start in boundaries() {
+        if !haystack_escaped[start..].starts_with(needle_escaped) {
+            continue;
+        }
+        let end = start + needle_escaped.len();
+        while end_boundaries.next_if(|&boundary| boundary < end).is_some() {}
+        if end_boundaries.peek() == Some(&end) {
+            return Some(start);
+        }
+    }
+    None
+}
+
+// Parses four hex digits starting at bytes[start], if they're all there.
+fn hex4_at(bytes: &[u8], start: usize) -> Option<u16> {
+    let digits = bytes.get(start..start + 4)?;
+    let mut value = 0;
+    for &digit in digits {
+        value = value * 0x10 + (digit as char).to_digit(16)? as u16;
+    }
+    Some(value)
+}
+
+// Returns whether a (raw, unescaped) string contains anything a terminal
+// might interpret as the start of a control sequence, rather than printing
+// it. This is meant to be checked before printing untrusted data with
+// control characters left unescaped. Specifically, we look for:
+//
+// - ESC (U+001B), which introduces all 7-bit sequences, including CSI
+//   ("ESC [", e.g., "\x1b[2J" to clear the screen) and OSC ("ESC ]",
+//   e.g., to set the window title or write to the clipboard)
+// - The 8-bit C1 equivalents of those introducers: DCS (U+0090),
+//   SOS (U+0098), CSI (U+009B), OSC (U+009D), PM (U+009E) and APC (U+009F)
+#[allow(dead_code)]
+pub fn contains_dangerous_sequences(s: &str) -> bool {
+    s.chars().any(is_sequence_introducer)
+}
+
+// Returns whether an escaped string can be unescaped with control characters
+// left unescaped (as unsafe_unescape_json_string does) and printed, without
+// emitting anything contains_dangerous_sequences looks for. Stops at the
+// first dangerous char, whether it's escaped or raw. Strings that fail to
+// unescape are printed as is, so they're only unsafe if they contain a raw
+// dangerous char.
+#[allow(dead_code)]
+pub fn is_terminal_safe(escaped: &str) -> bool {
+    let config = UnescapeConfig {
+        escape_control_characters: false,
+        ..UnescapeConfig::default()
+    };
+    for decoded in UnescapeIter::new(escaped, &config) {
+        match decoded {
+            Ok(decoded) if is_sequence_introducer(decoded.ch) => return false,
+            Ok(_) => {}
+            Err(_) => return !contains_dangerous_sequences(escaped),
+        }
+    }
+    true
+}
+
+// Returns whether a (raw, unescaped) string contains a word that mixes
+// characters from different scripts, e.g., "pаypal" with a Cyrillic 'а',
+// which could be used to spoof a look-alike Latin word.
+//
+// Words are split on whitespace, so a value with separate Latin and Cyrillic
+// words isn't suspicious. Within a word, characters common to all scripts
+// (digits, punctuation, combining marks) are ignored, and scripts commonly
+// written together (e.g., Han and Hiragana in Japanese) count as a single
+// script, per the "single script" check of Unicode Technical Standard #39.
+#[cfg(feature = "unicode-security")]
+pub fn detect_confusables(s: &str) -> bool {
+    use unicode_security::MixedScript;
+
+    s.split_whitespace().any(|word| !word.is_single_script())
+}
+
+// Returns whether [ch] is in one of the Unicode general categories of chars
+// that terminals can't be relied on to print: control (Cc), format (Cf,
+// e.g., U+200E LEFT-TO-RIGHT MARK), unassigned (Cn) and private use (Co).
+#[cfg(feature = "unicode-general-category")]
+pub fn is_non_printable(ch: char) -> bool {
+    use unicode_general_category::{get_general_category, GeneralCategory};
+
+    matches!(
+        get_general_category(ch),
+        GeneralCategory::Control
+            | GeneralCategory::Format
+            | GeneralCategory::Unassigned
+            | GeneralCategory::PrivateUse
+    )
+}
+
+fn is_sequence_introducer(ch: char) -> bool {
+    matches!(ch as u32, 0x1B | 0x90 | 0x98 | 0x9B | 0x9D..=0x9F)
+}
+
+pub fn is_control(ch: char) -> bool {
+    matches!(ch as u32, 0x00..=0x1F | 0x7F..=0x9F)
+}
+
+// Returns whether [ch] is a bidirectional embedding, override or isolate
+// control. These can reorder how surrounding text is displayed, making it
+// appear different from how it's actually stored (see "Trojan Source",
+// CVE-2021-42574).
+pub fn is_bidi_control(ch: char) -> bool {
+    matches!(ch as u32, 0x202A..=0x202E | 0x2066..=0x2069)
+}
+
+// Returns whether [ch] is an invisible zero-width character: a zero width
+// space, (non-)joiner, or zero width no-break space (also used as a byte
+// order mark). These can hide content, or make otherwise identical looking
+// strings differ.
+pub fn is_zero_width(ch: char) -> bool {
+    matches!(ch, '\u{200B}' | '\u{200C}' | '\u{200D}' | '\u{FEFF}')
+}
+
+// Consumes four hex characters from a Chars iterator, and converts it to a u16.
+// Also returns the four original characters as a mini [u8] that can be safely
+// interpreted as a str. If the characters aren't all hex digits, the chars up
+// to and including the first invalid one are consumed, and an error is
+// returned.
+fn parse_codepoint_from_chars(
+    chars: &mut std::str::Chars<'_>,
+) -> Result<(u16, [u8; 4]), UnescapeErrorKind> {
+    let digits = chars.as_str();
+    let consumed = |chars: &std::str::Chars| digits.len() - chars.as_str().len();
+
+    let mut codepoint = 0;
+    for _ in 0..4 {
+        match chars.next().and_then(hex_char_to_int) {
+            Some(digit) => codepoint = codepoint * 0x10 + digit,
+            None => {
+                let escape = format!("\\u{}", &digits[..consumed(chars)]);
+                return Err(UnescapeErrorKind::InvalidHexEscape(escape));
+            }
+        }
+    }
+
+    // Hex digits are ASCII, so four chars are four bytes.
+    debug_assert_eq!(4, consumed(chars), "parse_codepoint_from_chars consumed wrong length");
+    let bytes = digits.as_bytes();
+    let codepoint_chars = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    // The Display impl for UnescapeErrorKind unwraps str::from_utf8 on these,
+    // so never let anything else into an error.
+    if !is_valid_hex4(&codepoint_chars) {
+        let escape = format!("\\u{}", String::from_utf8_lossy(&codepoint_chars));
+        return Err(UnescapeErrorKind::InvalidHexEscape(escape));
+    }
+    Ok((codepoint, codepoint_chars))
+}
+
+// Whether [chars] are four ASCII hex digits, i.e., valid contents for the
+// codepoint_chars of an UnescapeErrorKind.
+fn is_valid_hex4(chars: &[u8; 4]) -> bool {
+    chars.iter().all(u8::is_ascii_hexdigit)
+}
+
+fn hex_char_to_int(ch: char) -> Option<u16> {
+    match ch {
+        '0'..='9' => Some((ch as u16) - ('0' as u16)),
+        'a'..='f' => Some((ch as u16) - ('a' as u16) + 10),
+        'A'..='F' => Some((ch as u16) - ('A' as u16) + 10),
+        _ => None,
+    }
+}
+
+// Interprets a codepoint in the Basic Multilingual Plane as either an actual
+// char, or one of a surrogate pair. The value associated with the surrogate
 // has had the offset removed.
 fn decode_codepoint(codepoint: u16) -> DecodedCodepoint {
     match codepoint {
@@ -301,74 +2620,2604 @@ fn decode_codepoint(codepoint: u16) -> DecodedCodepoint {
         0xDC00..=0xDFFF => DecodedCodepoint::LowSurrogate(codepoint - 0xDC00),
         _ => DecodedCodepoint::Char(char::from_u32(codepoint as u32).unwrap()),
     }
-}
+}
+
+// Combines the two UTF-16 code units of a surrogate pair (e.g., 0xD801 and
+// 0xDC37) into the char they encode (U+10437). Returns None if [high] isn't
+// a high surrogate, or [low] isn't a low surrogate.
+#[allow(dead_code)]
+pub fn combine_surrogates(high: u16, low: u16) -> Option<char> {
+    match (decode_codepoint(high), decode_codepoint(low)) {
+        (DecodedCodepoint::HighSurrogate(hs), DecodedCodepoint::LowSurrogate(ls)) => {
+            Some(surrogate_pair_char(hs, ls))
+        }
+        _ => None,
+    }
+}
+
+// Returns whether encoding [c] as UTF-16 and combining the resulting
+// surrogate pair with [combine_surrogates] produces [c] again. Chars in the
+// Basic Multilingual Plane are encoded as a single code unit, rather than a
+// surrogate pair, so this is trivially true for them.
+#[allow(dead_code)]
+pub fn surrogate_roundtrip(c: char) -> bool {
+    let mut utf16_buf = [0u16; 2];
+    match *c.encode_utf16(&mut utf16_buf) {
+        [_] => true,
+        [high, low] => combine_surrogates(high, low) == Some(c),
+        _ => false,
+    }
+}
+
+// Returns the byte range and code point of each surrogate pair escape
+// (e.g., "\ud83d\ude00" for U+1F600) in the escaped string [s], e.g., to
+// audit which chars outside the Basic Multilingual Plane a document
+// contains. Unmatched surrogates are skipped, and scanning stops at the
+// first invalid escape.
+#[allow(dead_code)]
+pub fn surrogate_pairs(s: &str) -> Vec<(Range<usize>, u32)> {
+    let config = UnescapeConfig {
+        surrogate_policy: SurrogatePolicy::Skip,
+        ..UnescapeConfig::default()
+    };
+    UnescapeIter::new(s, &config)
+        .map_while(Result::ok)
+        // Chars outside the BMP are either written literally (four bytes),
+        // or as a pair of "\uXXXX" escapes (twelve bytes).
+        .filter(|decoded| decoded.source.len() == 12 && decoded.ch > '\u{FFFF}')
+        .map(|decoded| (decoded.source, decoded.ch as u32))
+        .collect()
+}
+
+// Combines a surrogate pair, as decoded by [decode_codepoint] (i.e., with
+// their offsets removed), into a char.
+fn surrogate_pair_char(hs: u16, ls: u16) -> char {
+    let codepoint = (hs as u32) * 0x400 + (ls as u32) + 0x10000;
+    char::from_u32(codepoint).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks that [s] is a valid JSON string body (i.e., a string literal
+    // without the surrounding quotes), per RFC 8259:
+    //
+    //   char = unescaped /
+    //       escape (
+    //           %x22 /          ; "    quotation mark  U+0022
+    //           %x5C /          ; \    reverse solidus U+005C
+    //           %x2F /          ; /    solidus         U+002F
+    //           %x62 /          ; b    backspace       U+0008
+    //           %x66 /          ; f    form feed       U+000C
+    //           %x6E /          ; n    line feed       U+000A
+    //           %x72 /          ; r    carriage return U+000D
+    //           %x74 /          ; t    tab             U+0009
+    //           %x75 4HEXDIG )  ; uXXXX                U+XXXX
+    //
+    //   unescaped = %x20-21 / %x23-5B / %x5D-10FFFF
+    fn is_valid_json_string_body(s: &str) -> bool {
+        let mut chars = s.chars();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' => match chars.next() {
+                    Some('"' | '\\' | '/' | 'b' | 'f' | 'n' | 'r' | 't') => {}
+                    Some('u') => {
+                        for _ in 0..4 {
+                            if !chars.next().map_or(false, |ch| ch.is_ascii_hexdigit()) {
+                                return false;
+                            }
+                        }
+                    }
+                    _ => return false,
+                },
+                '"' | '\x00'..='\x1f' => return false,
+                _ => {}
+            }
+        }
+        true
+    }
+
+    // A straightforward implementation of find_range_from_escaped: escape
+    // the whole string, recording where each char ends up, and find all the
+    // chars whose escaped form overlaps [escaped_range]. If none do, the
+    // result is an empty range at the first char boundary at or after the
+    // start of [escaped_range].
+    fn find_range_reference(
+        unescaped: &str,
+        escaped_range: Range<usize>,
+    ) -> (Range<usize>, Range<usize>) {
+        let mut escaped = String::new();
+        let mut spans = vec![];
+        for (index, c) in unescaped.char_indices() {
+            let escaped_start = escaped.len();
+            escaped.push_str(&escape_json_string(&c.to_string()));
+            spans.push((index..index + c.len_utf8(), escaped_start..escaped.len()));
+        }
+
+        let overlapping: Vec<_> = spans
+            .iter()
+            .filter(|(_, e)| e.end > escaped_range.start && e.start < escaped_range.end)
+            .collect();
+        match (overlapping.first(), overlapping.last()) {
+            (Some((first_u, first_e)), Some((last_u, last_e))) => {
+                (first_u.start..last_u.end, first_e.start..last_e.end)
+            }
+            _ => {
+                let (u, e) = spans
+                    .iter()
+                    .find(|(_, e)| e.end > escaped_range.start)
+                    .map_or((unescaped.len(), escaped.len()), |(u, e)| (u.start, e.start));
+                (u..u, e..e)
+            }
+        }
+    }
+
+    // A tiny deterministic PRNG (xorshift64), so we can generate lots of
+    // test inputs without pulling in a dependency.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        // Generates a string mixing plain ASCII with all the characters that
+        // need special handling when escaping or unescaping.
+        fn string(&mut self, max_len: usize) -> String {
+            const CHARS: &[char] = &[
+                'a', 'Z', '0', ' ', '/', '"', '\\', '\x00', '\x08', '\t', '\n', '\x0c', '\r',
+                '\x1b', '\x1f', '\x7f', '\u{85}', '\u{9f}', 'é', '€', '中', '\u{2028}',
+                '\u{feff}', '😀', '𐐷', '\u{10ffff}',
+            ];
+            let len = self.next() as usize % (max_len + 1);
+            (0..len)
+                .map(|_| CHARS[self.next() as usize % CHARS.len()])
+                .collect()
+        }
+    }
+
+    #[track_caller]
+    fn check(escaped: &str, expected_unescaped: &str) {
+        let unescaped = match safe_unescape_json_string(escaped) {
+            Ok(s) => s,
+            Err(err) => format!("ERR: {err}"),
+        };
+
+        assert_eq!(expected_unescaped, &unescaped);
+    }
+
+    #[track_caller]
+    fn check_unsafe(escaped: &str, expected_unescaped: &str) {
+        let unescaped = match unsafe_unescape_json_string(escaped) {
+            Ok(s) => s,
+            Err(err) => format!("ERR: {err}"),
+        };
+
+        assert_eq!(expected_unescaped, &unescaped);
+    }
+
+    #[test]
+    fn test_unescape_json_string() {
+        // Ok
+        check("abc", "abc");
+        check("abc \\\\ \\\"", "abc \\ \"");
+        check("abc \\n \\t \\r", "abc \n \t \r");
+        check("€ \\u20AC", "€ \u{20AC}");
+        check("𐐷 \\uD801\\uDC37", "𐐷 \u{10437}");
+
+        // Control characters are escaped
+        check("12x\\b34", "12x\\b34");
+        check(
+            "\\u0000 | \\u001f | \\u0020 | \\u007e | \\u007f | \\u0080 | \\u009F | \\u00a0",
+            "\\u0000 | \\u001f | \u{0020} | \u{007e} | \\u007f | \\u0080 | \\u009F | \u{00a0}",
+        );
+
+        // But not if unsafe is called
+        check_unsafe("12x\\b34", "12x\x0834");
+        check_unsafe(
+            "\\u0000 | \\u001f | \\u0020 | \\u007e | \\u007f | \\u0080 | \\u009F | \\u00a0",
+            "\x00 | \x1f | \u{0020} | \u{007e} | \x7f | \u{0080} | \u{009F} | \u{00a0}",
+        );
+
+        // Non-ASCII unescaped control codes also get escaped
+        check("12 \u{0080} 34", "12 \\u0080 34");
+
+        // But not if unsafe is called
+        check_unsafe("12 \u{0080} 34", "12 \u{0080} 34");
+
+        // Errors; make sure index is computed properly.
+        check(
+            "abc 𐐷 \\uD801\\uDC37 \\uD801",
+            // "abc 𐐷 \uD801\uDC37 \uD801"
+            // 0 2 4 6 8 0 2 4 6 8 0 2 4 6
+            "ERR: unescaping error at char 26: high surrogate \"\\uD801\" not followed by low surrogate",
+        );
+
+        check(
+            "abc 𐐷 \\uD801\\uDC37 \\uDC37",
+            // "abc 𐐷 \uD801\uDC37 \uDC37"
+            // 0 2 4 6 8 0 2 4 6 8 0 2 4 6
+            "ERR: unescaping error at char 20: unexpected low surrogate \"\\uDC37\"",
+        );
+    }
+
+    #[test]
+    fn test_unsafe_unescape_json_string() {
+        // Built for tests even without the sexp feature.
+        check_unsafe("a\\u0000b\\tc", "a\u{0}b\tc");
+        check_unsafe("\\u001B[0m", "\u{1b}[0m");
+        check_unsafe(
+            "\\uDE00",
+            "ERR: unescaping error at char 1: unexpected low surrogate \"\\uDE00\"",
+        );
+    }
+
+    #[test]
+    fn test_unescape_ascii() {
+        let config = UnescapeConfig::default();
+        for s in ["", "abc", "\\u00e9\\uD83D\\uDE00\\n\\u001b\\\"", "\\/x\\u4e2d"] {
+            assert_eq!(
+                safe_unescape_json_string(s).unwrap(),
+                unescape_ascii(s.as_bytes(), &config).unwrap()
+            );
+        }
+
+        let err = unescape_ascii("ab\\u00e9cé".as_bytes(), &config).unwrap_err();
+        assert_eq!(
+            "unescaping error at char 10: non-ASCII byte 0xC3 in ASCII input",
+            err.to_string()
+        );
+        assert_eq!(9..10, err.byte_range());
+        assert_eq!(None, err.codepoint());
+
+        // Escaping errors are still reported.
+        assert!(unescape_ascii(b"\\uDE00", &config).is_err());
+    }
+
+    #[test]
+    fn test_unescape_require_ascii() {
+        assert_eq!("A", unescape_require_ascii("\\u0041").unwrap());
+        assert_eq!("key_1 \"\\u001b\"", unescape_require_ascii("key_1 \\\"\\u001b\\\"").unwrap());
+
+        let err = unescape_require_ascii("price: \\u20AC5").unwrap_err();
+        assert_eq!(
+            "unescaping error at char 8: non-ASCII char '€' (U+20AC) not allowed",
+            err.to_string()
+        );
+        assert_eq!(7..13, err.byte_range());
+
+        let err = unescape_require_ascii("é\\ud83d\\ude00").unwrap_err();
+        assert_eq!(0..2, err.byte_range());
+        let err = unescape_require_ascii("ab\\ud83d\\ude00").unwrap_err();
+        assert_eq!(
+            "unescaping error at char 3: non-ASCII char '😀' (U+1F600) not allowed",
+            err.to_string()
+        );
+        assert_eq!(2..14, err.byte_range());
+
+        // Other errors are still reported.
+        assert!(unescape_require_ascii("\\q").is_err());
+    }
+
+    #[test]
+    fn test_unescape_fully() {
+        let config = UnescapeConfig::default();
+        let fully = |s: &str, max_rounds: usize| unescape_fully(s, max_rounds, &config).unwrap();
+
+        let double_escaped = "caf\\\\u00e9 \\\\\\\"x\\\\\\\"";
+        assert_eq!(double_escaped, fully(double_escaped, 0));
+        assert_eq!("caf\\u00e9 \\\"x\\\"", fully(double_escaped, 1));
+        assert_eq!("café \"x\"", fully(double_escaped, 2));
+        assert_eq!("café \"x\"", fully(double_escaped, 100));
+
+        // Each round halves a run of backslashes, until a lone '\\' is
+        // left, which isn't a valid escaped string.
+        let deep = "\\".repeat(1 << 10);
+        assert_eq!("\\".repeat(1 << 7), fully(&deep, 3));
+        assert_eq!("\\", fully(&deep, 100));
+
+        // Safe unescaping leaves controls escaped, so they stabilize.
+        assert_eq!("\\u001b", fully("\\\\u001b", 100));
+
+        // Only errors in the first round are reported.
+        assert!(unescape_fully("\\q", 2, &config).is_err());
+        assert_eq!("C:\\dir", fully("C:\\\\dir", 2));
+    }
+
+    #[test]
+    fn test_unescape_json_string_or_log() {
+        let mut errors = vec![];
+        let unescaped = unescape_json_string_or_log("bad \\ud83d surrogate", |err| {
+            errors.push(err.to_string())
+        });
+        assert_eq!("bad \\ud83d surrogate", unescaped);
+        assert_eq!(
+            vec![
+                "unescaping error at char 11: \
+                 high surrogate \"\\ud83d\" not followed by low surrogate"
+            ],
+            errors
+        );
+
+        let unescaped = unescape_json_string_or_log("caf\\u00e9", |_| panic!("no error expected"));
+        assert_eq!("café", unescaped);
+        assert_eq!("bad \\ud83d", unescape_json_string_unwrap("bad \\ud83d"));
+    }
+
+    #[test]
+    fn test_unescape_prefix() {
+        #[track_caller]
+        fn check_prefix(escaped: &str, n: usize, expected: &str, expected_truncated: bool) {
+            let (prefix, truncated) =
+                unescape_prefix(escaped, n, &UnescapeConfig::default()).unwrap();
+            assert_eq!(expected, prefix);
+            assert_eq!(expected_truncated, truncated);
+        }
+
+        check_prefix("abc", 0, "", true);
+        check_prefix("abc", 2, "ab", true);
+        check_prefix("abc", 3, "abc", false);
+        check_prefix("abc", 10, "abc", false);
+        check_prefix("", 1, "", false);
+
+        // Surrogate pairs and escaped control characters are single chars.
+        check_prefix("\\uD83D\\uDE00\\u0001xyz", 2, "😀\\u0001", true);
+        check_prefix("\\uD83D\\uDE00\\u0001xyz", 5, "😀\\u0001xyz", false);
+        check_prefix("a\\nb", 2, "a\n", true);
+
+        // Errors after the prefix aren't reached.
+        check_prefix("ab\\uDE00", 2, "ab", true);
+        assert!(unescape_prefix("ab\\uDE00", 3, &UnescapeConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_unescape_prefix_bom() {
+        #[track_caller]
+        fn check_bom_prefix(escaped: &str, n: usize, bom_policy: BomPolicy, expected: &str) {
+            let config = UnescapeConfig {
+                bom_policy,
+                ..UnescapeConfig::default()
+            };
+            let (prefix, truncated) = unescape_prefix(escaped, n, &config).unwrap();
+            assert_eq!(expected, prefix);
+            assert!(truncated);
+        }
+
+        for escaped in ["\u{FEFF}abc", "\\ufeffabc"] {
+            check_bom_prefix(escaped, 1, BomPolicy::Keep, "\u{FEFF}");
+            check_bom_prefix(escaped, 2, BomPolicy::Keep, "\u{FEFF}a");
+            check_bom_prefix(escaped, 1, BomPolicy::Strip, "a");
+            check_bom_prefix(escaped, 2, BomPolicy::Strip, "ab");
+            check_bom_prefix(escaped, 1, BomPolicy::Escape, "\\ufeff");
+            check_bom_prefix(escaped, 2, BomPolicy::Escape, "\\ufeffa");
+        }
+        // Only the leading BOM is stripped.
+        check_bom_prefix("\u{FEFF}\u{FEFF}ab", 1, BomPolicy::Strip, "\u{FEFF}");
+
+        // Consistent with unescaped_char_count.
+        let config = UnescapeConfig {
+            bom_policy: BomPolicy::Strip,
+            ..UnescapeConfig::default()
+        };
+        let (prefix, truncated) = unescape_prefix("\\ufeffabc", 3, &config).unwrap();
+        assert_eq!(("abc", false), (prefix.as_str(), truncated));
+        assert_eq!(3, unescaped_char_count("\\ufeffabc", &config).unwrap());
+    }
+
+    #[test]
+    fn test_unescaped_char_count() {
+        let config = UnescapeConfig::default();
+        let escaped = "a\\ud83d\\ude00\\n\\t\\\"b\\uD801\\uDC37\\u00e9\\\\";
+        assert_eq!(9, unescaped_char_count(escaped, &config).unwrap());
+        assert_eq!(
+            unescape_json_string(escaped, &config).unwrap().chars().count(),
+            unescaped_char_count(escaped, &config).unwrap()
+        );
+
+        // Escaped control characters count as a single char.
+        assert_eq!(2, unescaped_char_count("\\u001bx", &config).unwrap());
+        assert_eq!(0, unescaped_char_count("", &config).unwrap());
+        assert!(unescaped_char_count("a\\ud83d b", &config).is_err());
+
+        let config = UnescapeConfig {
+            surrogate_policy: SurrogatePolicy::Skip,
+            bom_policy: BomPolicy::Strip,
+            ..UnescapeConfig::default()
+        };
+        assert_eq!(3, unescaped_char_count("\\ufeffa\\ud83d b", &config).unwrap());
+    }
+
+    #[test]
+    fn test_surrogate_policy_replace() {
+        #[track_caller]
+        fn check_replace(escaped: &str, replacement: char, expected: &str) {
+            let config = UnescapeConfig {
+                surrogate_policy: SurrogatePolicy::Replace { replacement },
+                ..UnescapeConfig::default()
+            };
+            assert_eq!(expected, unescape_json_string(escaped, &config).unwrap());
+        }
+
+        for r in ['?', '\u{FFFD}'] {
+            // Stray low surrogate
+            check_replace("a\\uDE00b", r, &format!("a{r}b"));
+            // High surrogate at the end, or followed by a regular char
+            check_replace("a\\uD83D", r, &format!("a{r}"));
+            check_replace("a\\uD83Dbc", r, &format!("a{r}bc"));
+            // High surrogate followed by a non-surrogate escape, which is
+            // still decoded.
+            check_replace("\\uD83D\\u0041", r, &format!("{r}A"));
+            // Two high surrogates, the second of which is paired.
+            check_replace("\\uD83D\\uD83D\\uDE00", r, &format!("{r}😀"));
+            // Valid pairs are unaffected.
+            check_replace("\\uD83D\\uDE00", r, "😀");
+        }
+    }
+
+    #[test]
+    fn test_unescaped_eq() {
+        let config = UnescapeConfig::default();
+        let eq = |a: &str, b: &str| unescaped_eq(a, b, &config).unwrap();
+
+        assert!(eq("\\u0041", "A"));
+        assert!(!eq("\\u0041", "B"));
+        assert!(eq("", ""));
+        assert!(eq("\\uD83D\\uDE00 \\/", "😀 /"));
+        assert!(eq("\\u001b", "\\u001B"));
+        assert!(!eq("ab", "abc"));
+        assert!(!eq("abc", "ab"));
+
+        // Stops at the first difference.
+        assert!(!eq("a\\uDE00", "b\\uDE00"));
+        assert!(unescaped_eq("a\\uDE00", "a\\uDE00", &config).is_err());
+    }
+
+    #[test]
+    fn test_safe_unescape_is_noop() {
+        #[track_caller]
+        fn check_noop(s: &str, expected: bool) {
+            assert_eq!(expected, safe_unescape_is_noop(s));
+            if expected {
+                assert_eq!(s, safe_unescape_json_string(s).unwrap());
+            }
+        }
+
+        check_noop("", true);
+        check_noop("plain text", true);
+        check_noop("😀 café", true);
+        check_noop("a\\u001bb\\bc\\u007F", true);
+
+        check_noop("a\\nb", false);
+        check_noop("\\\"", false);
+        check_noop("\\u0041", false);
+        check_noop("\\uD83D\\uDE00", false);
+        check_noop("\\/", false);
+        // Raw control chars get re-escaped.
+        check_noop("a\u{1b}b", false);
+        check_noop("a\\qb", false);
+        check_noop("\\uDE00", false);
+    }
+
+    #[test]
+    fn test_unescape_error_display_with_source() {
+        #[track_caller]
+        fn check_pointer(escaped: &str, expected: &str) {
+            let err = safe_unescape_json_string(escaped).unwrap_err();
+            assert_eq!(format!("{err}"), format!("{}", err.display_with_source(escaped)));
+            assert_eq!(expected, format!("{:#}", err.display_with_source(escaped)));
+        }
+
+        check_pointer(
+            "ab\\qcd",
+            "unescaping error at char 3: invalid escape sequence \"\\q\"\n\
+             ab\\qcd\n  ^^",
+        );
+        check_pointer(
+            "中 \\uDE00!",
+            "unescaping error at char 3: unexpected low surrogate \"\\uDE00\"\n\
+             中 \\uDE00!\n   ^^^^^^",
+        );
+        check_pointer(
+            "\\uD83Dx",
+            "unescaping error at char 7: high surrogate \"\\uD83D\" not followed by low surrogate\n\
+             \\uD83Dx\n^^^^^^",
+        );
+    }
+
+    #[test]
+    fn test_unescape_error_display_column() {
+        let config = WidthConfig::default();
+
+        let source = "ab\\uDE00";
+        let err = safe_unescape_json_string(source).unwrap_err();
+        assert_eq!(2, err.display_column(source, config));
+
+        // Wide chars take two columns, and escapes one per byte.
+        let source = "中文\\n\\uDE00";
+        let err = safe_unescape_json_string(source).unwrap_err();
+        assert_eq!(5, err.index);
+        assert_eq!(6, err.display_column(source, config));
+
+        // U+00B1 (±) has ambiguous width.
+        let source = "±\\q";
+        let err = safe_unescape_json_string(source).unwrap_err();
+        assert_eq!(1, err.display_column(source, config));
+        assert_eq!(2, err.display_column(source, WidthConfig { cjk: true }));
+    }
+
+    #[test]
+    fn test_surrogate_policy_skip() {
+        #[track_caller]
+        fn check_skip(escaped: &str, expected: &str) {
+            let config = UnescapeConfig {
+                surrogate_policy: SurrogatePolicy::Skip,
+                ..UnescapeConfig::default()
+            };
+            assert_eq!(expected, unescape_json_string(escaped, &config).unwrap());
+        }
+
+        // Stray low surrogate
+        check_skip("a\\uDE00b", "ab");
+        check_skip("\\uDE00\\uDE00", "");
+        // High surrogate at the end, or followed by a regular char
+        check_skip("a\\uD83D", "a");
+        check_skip("a\\uD83Dbc", "abc");
+        // High surrogate followed by a non-surrogate escape
+        check_skip("\\uD83D\\u0041", "A");
+        // Two high surrogates: only the first is dropped, and the second
+        // pairs with the following low surrogate.
+        check_skip("\\uD83D\\uD83D\\uDE00", "😀");
+        check_skip("\\uD83D\\uD83D", "");
+        // Valid pairs are unaffected.
+        check_skip("\\uD83D\\uDE00", "😀");
+
+        // Skipped surrogates don't count towards a prefix.
+        let config = UnescapeConfig {
+            surrogate_policy: SurrogatePolicy::Skip,
+            ..UnescapeConfig::default()
+        };
+        assert_eq!(
+            ("ab".to_owned(), true),
+            unescape_prefix("a\\uDE00bc", 2, &config).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_codepoint_from_chars() {
+        let mut chars = "D83dxyz".chars();
+        let (codepoint, codepoint_chars) = parse_codepoint_from_chars(&mut chars).unwrap();
+        assert_eq!(0xD83D, codepoint);
+        assert_eq!(b"D83d", &codepoint_chars);
+        assert_eq!("xyz", chars.as_str());
+
+        let mut chars = "00e9".chars();
+        assert_eq!(0xE9, parse_codepoint_from_chars(&mut chars).unwrap().0);
+        assert_eq!("", chars.as_str());
+
+        let mut chars = "12G4x".chars();
+        assert!(parse_codepoint_from_chars(&mut chars).is_err());
+        assert_eq!("4x", chars.as_str());
+
+        let mut chars = "12".chars();
+        assert!(parse_codepoint_from_chars(&mut chars).is_err());
+
+        check(
+            "a\\u12G4",
+            "ERR: unescaping error at char 2: invalid hex digits in escape sequence \"\\u12G\"",
+        );
+        check(
+            "\\u12",
+            "ERR: unescaping error at char 1: invalid hex digits in escape sequence \"\\u12\"",
+        );
+        check(
+            "\\uD83D\\u12",
+            "ERR: unescaping error at char 13: high surrogate \"\\uD83D\" not followed by low surrogate",
+        );
+    }
+
+    #[test]
+    fn test_trailing_backslash() {
+        check("a\\", "ERR: unescaping error at char 2: incomplete escape sequence at end of input");
+        check("a\\\\\\", "ERR: unescaping error at char 4: incomplete escape sequence at end of input");
+        check("\\", "ERR: unescaping error at char 1: incomplete escape sequence at end of input");
+        check("a\\\\", "a\\");
+
+        let config = UnescapeConfig::default();
+        let err = safe_unescape_json_string("foo\\").unwrap_err();
+        assert_eq!(3..4, err.byte_range());
+        assert!(prepare_query("foo\\", &QueryConfig::default()).is_err());
+        assert!(is_terminal_safe("foo\\"));
+        assert!(!is_terminal_safe("\x1bfoo\\"));
+        assert!(!safe_unescape_is_noop("foo\\"));
+        assert_eq!(1, surrogate_pairs("\\ud83d\\ude00\\").len());
+        assert!(unescaped_char_count("foo\\", &config).is_err());
+        assert!(canonicalize_escapes("foo\\", EscapeStyle::Json).is_err());
+        assert!(unescape_require_ascii("foo\\").is_err());
+        assert_eq!("a\\", unescape_fully("a\\\\", 3, &config).unwrap());
+    }
+
+    #[test]
+    fn test_reject_escaped_slash() {
+        let config = UnescapeConfig {
+            reject_escaped_slash: true,
+            ..UnescapeConfig::default()
+        };
+        let err = unescape_json_string("a\\/b", &config).unwrap_err();
+        assert_eq!(
+            "unescaping error at char 2: invalid escape sequence \"\\/\"",
+            format!("{err}")
+        );
+        assert_eq!("a/b", unescape_json_string("a/b", &config).unwrap());
+
+        check("a\\/b", "a/b");
+    }
+
+    #[test]
+    fn test_strict_rfc8259() {
+        let config = UnescapeConfig {
+            strict_rfc8259: true,
+            ..UnescapeConfig::default()
+        };
+        let err = unescape_json_string("ab\ncd", &config).unwrap_err();
+        assert_eq!(
+            "unescaping error at char 3: unescaped control character U+000A",
+            format!("{err}")
+        );
+        assert_eq!(2..3, err.byte_range());
+        assert!(unescape_json_string("\u{0}", &config).is_err());
+        assert!(unescape_json_string("é\t", &config).is_err());
+
+        // Escaped controls are fine, and so is a raw DEL.
+        assert_eq!("ab\ncd", unescape_json_string("ab\\ncd", &config).unwrap());
+        assert_eq!("\\u001b\\u007F", unescape_json_string("\\u001b\u{7f}", &config).unwrap());
+
+        check("ab\ncd", "ab\\u000Acd");
+    }
+
+    #[test]
+    fn test_prefer_short_escapes() {
+        let config = UnescapeConfig {
+            prefer_short_escapes: true,
+            ..UnescapeConfig::default()
+        };
+        let raw = "a\nb\tc\rd\x08e\x0cf\x1bg\x7f";
+        assert_eq!(
+            "a\\nb\\tc\\rd\\be\\ff\\u001Bg\\u007F",
+            unescape_json_string(raw, &config).unwrap()
+        );
+        check(raw, "a\\u000Ab\\u0009c\\u000Dd\\u0008e\\u000Cf\\u001Bg\\u007F");
+
+        // Escapes from the source are left as written.
+        assert_eq!("\\u0008\\b", unescape_json_string("\\u0008\\b", &config).unwrap());
+        // Only applies when rendering controls as escapes.
+        let named = UnescapeConfig {
+            control_rendering: ControlRendering::Named,
+            ..config
+        };
+        assert_eq!("<LF>", unescape_json_string("\n", &named).unwrap());
+    }
+
+    #[test]
+    fn test_short_escape_table() {
+        let table = ShortEscapeTable::default().with('a', '\x07').with('v', '\x0b');
+        assert_eq!(Some('\x07'), table.decode('a'));
+        assert_eq!(Some('\n'), table.decode('n'));
+        assert_eq!(None, table.decode('e'));
+        assert_eq!(Some('v'), table.encode('\x0b'));
+        assert_eq!(None, table.encode('\x1b'));
+
+        let unescape_config = UnescapeConfig {
+            escape_control_characters: false,
+            short_escapes: table.clone(),
+            ..UnescapeConfig::default()
+        };
+        let escape_config = EscapeConfig {
+            short_escapes: table,
+            ..EscapeConfig::default()
+        };
+        let raw = "bell\x07 tab\x0b\t nul\0 \"x\"";
+        let escaped = escape_json_string_with(raw, &escape_config).unwrap();
+        assert_eq!("bell\\a tab\\v\\t nul\\u0000 \\\"x\\\"", escaped);
+        assert_eq!(raw, unescape_json_string(&escaped, &unescape_config).unwrap());
+
+        // Safe unescaping leaves the new control escapes as written.
+        let safe_config = UnescapeConfig {
+            escape_control_characters: true,
+            ..unescape_config.clone()
+        };
+        assert_eq!("\\a\\v\n", unescape_json_string("\\a\\v\\n", &safe_config).unwrap());
+
+        // Entries can be replaced.
+        let nul_config = UnescapeConfig {
+            short_escapes: ShortEscapeTable::default().with('n', '\0'),
+            ..unescape_config
+        };
+        assert_eq!("\0", unescape_json_string("\\n", &nul_config).unwrap());
+
+        // Not recognized by default.
+        assert!(safe_unescape_json_string("\\a").is_err());
+        assert_eq!("\\u0007", escape_json_string("\x07"));
+    }
+
+    #[test]
+    fn test_unescape_writer() {
+        use std::io::Write as _;
+
+        let config = UnescapeConfig::default();
+
+        let mut writer = UnescapeWriter::new(Vec::new(), &config);
+        for piece in ["\\u", "D8", "01\\", "uDC", "37"] {
+            writer.write_all(piece.as_bytes()).unwrap();
+        }
+        assert_eq!("\u{10437}".as_bytes(), &writer.finish().unwrap()[..]);
+
+        // Split at every possible pair of points, including in the middle of
+        // multi-byte chars and escapes.
+        let escaped = "a\\\"€\\n\\uD83D\\uDE00 \\u001b\\uD83D中\\\\\\/";
+        let expected = "a\"€\n😀 \\u001b\\uD83D中\\/";
+        let config = UnescapeConfig {
+            surrogate_policy: SurrogatePolicy::Replace { replacement: '?' },
+            ..UnescapeConfig::default()
+        };
+        let expected = expected.replace("\\uD83D", "?");
+        let bytes = escaped.as_bytes();
+        for i in 0..=bytes.len() {
+            for j in i..=bytes.len() {
+                let mut writer = UnescapeWriter::new(Vec::new(), &config);
+                writer.write_all(&bytes[..i]).unwrap();
+                writer.write_all(&bytes[i..j]).unwrap();
+                writer.write_all(&bytes[j..]).unwrap();
+                let unescaped = writer.finish().unwrap();
+                assert_eq!(expected, String::from_utf8(unescaped).unwrap());
+            }
+        }
+
+        // A byte order mark is only handled at the start.
+        let config = UnescapeConfig {
+            bom_policy: BomPolicy::Strip,
+            ..UnescapeConfig::default()
+        };
+        let mut writer = UnescapeWriter::new(Vec::new(), &config);
+        writer.write_all(b"\\ufeffa").unwrap();
+        writer.write_all(b"\\ufeffb").unwrap();
+        assert_eq!("a\u{feff}b".as_bytes(), &writer.finish().unwrap()[..]);
+
+        // Errors
+        let config = UnescapeConfig::default();
+        let mut writer = UnescapeWriter::new(Vec::new(), &config);
+        assert!(writer.write_all(b"a\\qb").is_err());
+        let mut writer = UnescapeWriter::new(Vec::new(), &config);
+        writer.write_all(b"a\\u00").unwrap();
+        assert!(writer.finish().is_err());
+        let mut writer = UnescapeWriter::new(Vec::new(), &config);
+        writer.write_all(b"a\\").unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn test_unescape_writer_column() {
+        use std::io::Write as _;
+
+        let config = UnescapeConfig::default();
+        let mut writer = UnescapeWriter::new(Vec::new(), &config);
+        assert_eq!(0, writer.column());
+
+        let mut write = |piece: &str| {
+            writer.write_all(piece.as_bytes()).unwrap();
+            writer.column()
+        };
+        assert_eq!(2, write("ab"));
+        // Reset by each newline.
+        assert_eq!(2, write("cd\\n\u{4e2d}"));
+        assert_eq!(1, write("\u{6587}\\nx"));
+        assert_eq!(3, write("\\u4e2d"));
+        // Control characters that are left escaped are printed as escapes.
+        assert_eq!(9, write("\\u001b"));
+        // Incomplete escapes aren't counted until they're completed.
+        assert_eq!(12, write("caf\\u00"));
+        assert_eq!(13, write("e9"));
+        assert_eq!(13, write("\\ud83d"));
+        assert_eq!(15, write("\\ude00"));
+        assert_eq!(0, write("\\r\\n"));
+        // An escaped backslash is a single column, and doesn't affect the
+        // width of the char after it.
+        assert_eq!(3, write("\\\\\u{4e2d}"));
+        assert_eq!(4, write("\\\\\u{301}"));
+    }
+
+    #[test]
+    fn test_unescape_lines() {
+        let unescape = |input: &str| {
+            let mut out = vec![];
+            unescape_lines(input.as_bytes(), &mut out, &UnescapeConfig::default())
+                .map(|()| String::from_utf8(out).unwrap())
+        };
+
+        assert_eq!("", unescape("").unwrap());
+        assert_eq!(
+            "caf\u{e9} \"1\"\n\u{1f600} \\u001b!\n",
+            unescape("caf\\u00e9 \\\"1\\\"\n\\ud83d\\ude00 \\u001b!\n").unwrap()
+        );
+        // Line endings are preserved, including a missing one at the end.
+        assert_eq!("a\tb\r\n\nc", unescape("a\\tb\r\n\nc").unwrap());
+
+        let err = unescape("ok\n\\ud83d\n\\ude00\n").unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!(
+            "line 2: unescaping error at char 7: \
+             high surrogate \"\\ud83d\" not followed by low surrogate",
+            err.to_string()
+        );
+        let err = unescape("ok\nbad\\\nok\n").unwrap_err();
+        assert_eq!(
+            "line 2: unescaping error at char 4: incomplete escape sequence at end of input",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_escape_bidi_controls() {
+        // Unescaping, with control characters unescaped
+        let config = UnescapeConfig {
+            escape_control_characters: false,
+            escape_bidi_controls: true,
+            ..UnescapeConfig::default()
+        };
+        let unescape = |s: &str| unescape_json_string(s, &config).unwrap();
+        assert_eq!("a\\u202eb", unescape("a\\u202eb"));
+        assert_eq!("a\\u202Eb\\u2066", unescape("a\u{202e}b\u{2066}"));
+        assert_eq!(
+            "\u{1b} \u{202f} \u{2065} ordinary text",
+            unescape("\\u001b \u{202f} \u{2065} ordinary text")
+        );
+
+        let config = UnescapeConfig {
+            escape_bidi_controls: true,
+            control_rendering: ControlRendering::Named,
+            ..UnescapeConfig::default()
+        };
+        assert_eq!("<U+202E><ESC>", unescape_json_string("\\u202e\\u001b", &config).unwrap());
+
+        // Off by default
+        check("a\\u202eb", "a\u{202e}b");
+
+        // Escaping
+        let config = EscapeConfig {
+            style: EscapeStyle::Minimal,
+            escape_bidi_controls: true,
+            ..EscapeConfig::default()
+        };
+        let escape = |s: &str| escape_json_string_with(s, &config).unwrap();
+        assert_eq!("abc\\u202edef\\u2069", escape("abc\u{202e}def\u{2069}"));
+        assert_eq!("ordinary text, 中文 😀", escape("ordinary text, 中文 😀"));
+        let config = EscapeConfig {
+            style: EscapeStyle::Minimal,
+            ..EscapeConfig::default()
+        };
+        assert_eq!("abc\u{202e}", escape_json_string_with("abc\u{202e}", &config).unwrap());
+    }
+
+    #[test]
+    fn test_escape_isolated_combining_marks() {
+        let config = EscapeConfig {
+            style: EscapeStyle::Minimal,
+            escape_isolated_combining_marks: true,
+            ..EscapeConfig::default()
+        };
+        let escape = |s: &str| escape_json_string_with(s, &config).unwrap();
+
+        // U+0301 is a combining acute accent, and U+0308 a combining diaeresis.
+        assert_eq!("\\u0301abc", escape("\u{301}abc"));
+        assert_eq!("a \\u0301b", escape("a \u{301}b"));
+        assert_eq!("\\n\\u0301", escape("\n\u{301}"));
+        assert_eq!("\\\"\\u0301", escape("\"\u{301}"));
+        assert_eq!("\\u0301\\u0308", escape("\u{301}\u{308}"));
+
+        // Marks with a base are passed through, even if there are several.
+        assert_eq!("cafe\u{301}", escape("cafe\u{301}"));
+        assert_eq!("e\u{301}\u{308}", escape("e\u{301}\u{308}"));
+        assert_eq!("中\u{301}", escape("中\u{301}"));
+
+        let config = EscapeConfig {
+            style: EscapeStyle::Minimal,
+            ..EscapeConfig::default()
+        };
+        assert_eq!("\u{301}abc", escape_json_string_with("\u{301}abc", &config).unwrap());
+    }
+
+    #[test]
+    fn test_escape_zero_width() {
+        // Unescaping
+        let config = UnescapeConfig {
+            escape_zero_width: true,
+            ..UnescapeConfig::default()
+        };
+        let unescape = |s: &str| unescape_json_string(s, &config).unwrap();
+        assert_eq!("pass\\u200bword", unescape("pass\\u200bword"));
+        assert_eq!("pass\\u200Bword", unescape("pass\u{200b}word"));
+        assert_eq!("\\u200C\\u200D\\uFEFF", unescape("\u{200c}\u{200d}\u{feff}"));
+        assert_eq!("\u{2060}\u{2009}", unescape("\u{2060}\u{2009}"));
+        // Off by default
+        check("pass\\u200bword", "pass\u{200b}word");
+
+        // Escaping
+        let config = EscapeConfig {
+            style: EscapeStyle::Minimal,
+            escape_zero_width: true,
+            ..EscapeConfig::default()
+        };
+        let escape = |s: &str| escape_json_string_with(s, &config).unwrap();
+        assert_eq!("pass\\u200bword", escape("pass\u{200b}word"));
+        assert_eq!("\\ufeff中文", escape("\u{feff}中文"));
+        let config = EscapeConfig {
+            style: EscapeStyle::Minimal,
+            ..EscapeConfig::default()
+        };
+        assert_eq!("a\u{200b}b", escape_json_string_with("a\u{200b}b", &config).unwrap());
+    }
+
+    #[test]
+    fn test_escape_variation_selectors() {
+        let config = EscapeConfig {
+            style: EscapeStyle::Minimal,
+            escape_variation_selectors: true,
+            ..EscapeConfig::default()
+        };
+        let escape = |s: &str| escape_json_string_with(s, &config).unwrap();
+        assert_eq!("\u{2764}\\ufe0f love", escape("\u{2764}\u{fe0f} love"));
+        assert_eq!("\u{263a}\\ufe0e", escape("\u{263a}\u{fe0e}"));
+        assert_eq!("😀 中文", escape("😀 中文"));
+
+        let config = EscapeConfig {
+            hex_case: HexCase::Upper,
+            ..config
+        };
+        assert_eq!(
+            "\u{2764}\\uFE0F",
+            escape_json_string_with("\u{2764}\u{fe0f}", &config).unwrap()
+        );
+
+        let config = EscapeConfig {
+            style: EscapeStyle::Minimal,
+            ..EscapeConfig::default()
+        };
+        assert_eq!(
+            "\u{2764}\u{fe0f}",
+            escape_json_string_with("\u{2764}\u{fe0f}", &config).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_is_valid_hex4() {
+        assert!(is_valid_hex4(b"D83d"));
+        assert!(is_valid_hex4(b"0000"));
+        assert!(is_valid_hex4(b"fFfF"));
+        assert!(!is_valid_hex4(b"D83g"));
+        assert!(!is_valid_hex4(b"12 4"));
+        assert!(!is_valid_hex4(&[b'1', b'2', b'3', 0xC3]));
+        assert!(!is_valid_hex4(&[0; 4]));
+    }
+
+    #[test]
+    fn test_unescape_copies_verbatim_runs() {
+        // Unescapes a char at a time, like unescape_json_string used to.
+        fn unescape_per_char(s: &str, config: &UnescapeConfig) -> Result<String, UnescapeError> {
+            let mut unescaped = String::with_capacity(s.len());
+            for decoded in UnescapeIter::new(s, config) {
+                push_unescaped_char(&mut unescaped, s, &decoded?, config);
+            }
+            apply_bom_policy(&mut unescaped, config.bom_policy);
+            Ok(unescaped)
+        }
+
+        let long_run = "plain text, caf\\u00e9 ".repeat(50);
+        let inputs = [
+            long_run.as_str(),
+            "",
+            "no escapes at all",
+            "\\n\\\\leading and trailing escapes\\t",
+            "raw\\ncontrols\\u0001 and \\u001b[0m ansi \\ud83d\\ude00 emoji",
+            "literal\tcontrols\u{85}and\u{202e}bidi\u{200b}zero width",
+            "\\ufeffBOM",
+            "bad \\ud800 surrogate",
+            "bad \\q escape",
+        ];
+        let configs = [
+            UnescapeConfig::default(),
+            UnescapeConfig {
+                escape_control_characters: false,
+                ..UnescapeConfig::default()
+            },
+            UnescapeConfig {
+                escape_bidi_controls: true,
+                escape_zero_width: true,
+                bom_policy: BomPolicy::Strip,
+                control_rendering: ControlRendering::Named,
+                ..UnescapeConfig::default()
+            },
+            UnescapeConfig {
+                strict_rfc8259: true,
+                surrogate_policy: SurrogatePolicy::Replace { replacement: '\u{FFFD}' },
+                ..UnescapeConfig::default()
+            },
+        ];
+        for config in &configs {
+            for input in inputs {
+                assert_eq!(
+                    format!("{:?}", unescape_per_char(input, config)),
+                    format!("{:?}", unescape_json_string(input, config)),
+                    "input: {input:?}, config: {config:?}",
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_json_literal() {
+        for src in ["", "abc", "say \"hi\"", "tab\there", "back\\slash\\", "caf\u{e9} \u{1F600}"] {
+            let literal = to_json_literal(src);
+            assert_eq!(format!("\"{}\"", escape_json_string(src)), literal);
+            assert_eq!(src, from_json_literal(&literal).unwrap());
+        }
+        assert_eq!("\"say \\\"hi\\\"\"", to_json_literal("say \"hi\""));
+
+        assert_eq!("a\\u001bb", from_json_literal("\"a\\u001bb\"").unwrap());
+        let err = from_json_literal("\"ab\\qc\"").unwrap_err();
+        assert_eq!(3..5, err.byte_range());
+    }
+
+    #[test]
+    fn test_from_json_literal_missing_quote() {
+        #[track_caller]
+        fn check_missing(literal: &str, expected: QuotePosition, expected_span: Range<usize>) {
+            let err = from_json_literal(literal).unwrap_err();
+            match err.error {
+                UnescapeErrorKind::MissingQuote { position } => assert_eq!(expected, position),
+                _ => panic!("unexpected error for {:?}: {}", literal, err),
+            }
+            assert_eq!(expected_span, err.byte_range());
+        }
+
+        assert_eq!("abc", from_json_literal("\"abc\"").unwrap());
+        check_missing("abc\"", QuotePosition::Start, 0..0);
+        check_missing("", QuotePosition::Start, 0..0);
+        check_missing("\"abc", QuotePosition::End, 4..4);
+        check_missing("\"", QuotePosition::End, 1..1);
+        // The final quote is escaped.
+        check_missing("\"abc\\\"", QuotePosition::End, 6..6);
+
+        let err = from_json_literal("abc\"").unwrap_err();
+        assert_eq!("unescaping error at char 1: missing opening quote", err.to_string());
+        let err = from_json_literal("\"abc").unwrap_err();
+        assert_eq!("unescaping error at char 5: missing closing quote", err.to_string());
+    }
+
+    #[test]
+    fn test_unescape_max_input_len() {
+        let s = "caf\\u00e9 \\ud83d\\ude00";
+        let mut config = UnescapeConfig {
+            max_input_len: Some(s.len() - 1),
+            ..UnescapeConfig::default()
+        };
+        let err = unescape_json_string(s, &config).unwrap_err();
+        assert_eq!(
+            "unescaping error at char 1: input of 22 bytes exceeds the limit of 21 bytes",
+            err.to_string()
+        );
+
+        config.max_input_len = Some(s.len());
+        assert_eq!("caf\u{e9} \u{1F600}", unescape_json_string(s, &config).unwrap());
+        config.max_input_len = Some(1000);
+        assert_eq!("caf\u{e9} \u{1F600}", unescape_json_string(s, &config).unwrap());
+    }
+
+    #[test]
+    fn test_surrogate_pairs() {
+        assert_eq!(
+            vec![(1..13, 0x1F600), (16..28, 0x10437)],
+            surrogate_pairs("a\\ud83d\\ude00 b \\ud801\\udc37")
+        );
+        // Literal astral chars, escaped backslashes, and unmatched
+        // surrogates aren't surrogate pair escapes.
+        assert_eq!(
+            vec![(20..32, 0x1F600)],
+            surrogate_pairs("\u{1F600} \\ud800 \\\\ud83d \\ud83d\\ude00\\udc37")
+        );
+        assert_eq!(Vec::<(Range<usize>, u32)>::new(), surrogate_pairs("no pairs \\u00e9"));
+    }
+
+    #[test]
+    fn test_escape_uniform_unicode_escapes() {
+        let mut config = EscapeConfig {
+            uniform_unicode_escapes: true,
+            ..EscapeConfig::default()
+        };
+        let escape = |config: &EscapeConfig| {
+            escape_json_string_with("a\nb\tc \"q\" \\ \x01 \u{e9}", config).unwrap()
+        };
+        assert_eq!(
+            "a\\u000ab\\u0009c \\u0022q\\u0022 \\u005c \\u0001 \\u00e9",
+            escape(&config)
+        );
+
+        config.style = EscapeStyle::CHex;
+        config.hex_case = HexCase::Upper;
+        config.short_escapes = ShortEscapeTable::default().with('a', '\x07');
+        assert_eq!(
+            "a\\u000Ab\\u0009c \\u0022q\\u0022 \\u005C \\u0001 \\u00E9",
+            escape(&config)
+        );
+        assert_eq!("\\u0007", escape_json_string_with("\x07", &config).unwrap());
+
+        config.uniform_unicode_escapes = false;
+        assert_eq!("a\\nb\\tc \\\"q\\\" \\\\ \\x01 \\u00E9", escape(&config));
+    }
+
+    #[test]
+    fn test_escape_matches_serde_json() {
+        let mut corpus: Vec<String> = vec![
+            String::new(),
+            "plain ascii text".to_owned(),
+            "say \"hi\" \\ back/slash".to_owned(),
+            "caf\u{e9} \u{4e2d}\u{6587} \u{1F600} \u{10437} \u{10FFFF}".to_owned(),
+            "\u{2028}\u{2029}\u{feff}\u{200b}\u{202e}".to_owned(),
+        ];
+        corpus.extend((0..0x100).filter_map(char::from_u32).map(String::from));
+        let mut rng = XorShift(0x5e7de);
+        corpus.extend((0..1000).map(|_| rng.string(30)));
+
+        for src in &corpus {
+            let serde_literal = serde_json::to_string(src).unwrap();
+            let serde_escaped = &serde_literal[1..serde_literal.len() - 1];
+
+            // serde_json only escapes what JSON requires: quotes, backslashes
+            // and C0 controls, using the same short escapes, and lowercase
+            // "\u00xx" for the rest. escape_json_string also escapes DEL,
+            // which terminals don't display, and every non-ASCII char, as
+            // "\uXXXX" (or a surrogate pair), so reconcile those. Neither
+            // escapes '/'.
+            let mut expected = String::new();
+            let mut utf16_buf = [0u16; 2];
+            for c in serde_escaped.chars() {
+                if c == '\x7f' || !c.is_ascii() {
+                    for utf16 in c.encode_utf16(&mut utf16_buf) {
+                        write!(expected, "\\u{:04x}", utf16).unwrap();
+                    }
+                } else {
+                    expected.push(c);
+                }
+            }
+            assert_eq!(expected, escape_json_string(src), "{:?}", src);
+
+            // So serde_json parses it back to the same string.
+            let literal = to_json_literal(src);
+            assert_eq!(*src, serde_json::from_str::<String>(&literal).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_escape_edge_whitespace() {
+        let config = EscapeConfig {
+            escape_edge_whitespace: true,
+            ..EscapeConfig::default()
+        };
+        let escape = |s: &str| escape_json_string_with(s, &config).unwrap();
+        assert_eq!("\\u0020\\u0020trimmed\\u0020\\u0020", escape("  trimmed  "));
+        assert_eq!("two words", escape("two words"));
+        assert_eq!("\\t\\u0020a b\\u0020\\t", escape("\t a b \t"));
+        assert_eq!("\\u0020\\u0020\\u0020", escape("   "));
+        assert_eq!("", escape(""));
+
+        let config = EscapeConfig::default();
+        assert_eq!("  trimmed  ", escape_json_string_with("  trimmed  ", &config).unwrap());
+    }
+
+    #[test]
+    fn test_codepoint_to_json_escape() {
+        let escape = |codepoint| codepoint_to_json_escape(codepoint, EscapeStyle::Json);
+        assert_eq!(Some("\\n".to_owned()), escape(0x0A));
+        assert_eq!(Some("\\u001b".to_owned()), escape(0x1B));
+        assert_eq!(Some("a".to_owned()), escape(0x61));
+        assert_eq!(Some("\\u20ac".to_owned()), escape(0x20AC));
+        assert_eq!(Some("\\ud801\\udc37".to_owned()), escape(0x10437));
+        assert_eq!(
+            Some("\\u{10437}".to_owned()),
+            codepoint_to_json_escape(0x10437, EscapeStyle::Json5)
+        );
+        assert_eq!(None, escape(0xD801));
+        assert_eq!(None, escape(0x110000));
+    }
+
+    #[test]
+    fn test_combine_surrogates() {
+        assert_eq!(Some('\u{10437}'), combine_surrogates(0xD801, 0xDC37));
+        assert_eq!(Some('😀'), combine_surrogates(0xD83D, 0xDE00));
+        assert_eq!(None, combine_surrogates(0xDC37, 0xD801));
+        assert_eq!(None, combine_surrogates(0xD801, 0xD801));
+        assert_eq!(None, combine_surrogates(0x0041, 0xDC37));
+
+        for c in ['\u{10000}', '\u{10437}', '\u{10FFFF}', '😀'] {
+            assert!(surrogate_roundtrip(c), "{:?} didn't round trip", c);
+        }
+        // Trivially true in the Basic Multilingual Plane
+        for c in ['\u{0}', 'A', '€', '\u{FFFF}'] {
+            assert!(surrogate_roundtrip(c), "{:?} didn't round trip", c);
+        }
+    }
+
+    #[test]
+    fn test_unescape_indices() {
+        let config = UnescapeConfig::default();
+        let indices = |s: &str| {
+            unescape_indices(s, &config)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+
+        assert_eq!(
+            vec![(0..1, 'a'), (1..7, 'é'), (7..19, '😀'), (19..21, '\n'), (21..27, '\u{1b}')],
+            indices("a\\u00e9\\uD83D\\uDE00\\n\\u001b")
+        );
+        assert_eq!(vec![(0..3, '€')], indices("€"));
+
+        let mut rng = XorShift(0x1d1ce5);
+        for _ in 0..100 {
+            let escaped = escape_json_string(&rng.string(20));
+            let indices = indices(&escaped);
+
+            // The ranges tile the input...
+            let mut end = 0;
+            for (range, _) in indices.iter() {
+                assert_eq!(end, range.start);
+                end = range.end;
+            }
+            assert_eq!(escaped.len(), end);
+
+            // ...and the chars are the unescaped value.
+            let chars: String = indices.iter().map(|&(_, c)| c).collect();
+            assert_eq!(unsafe_unescape_json_string(&escaped).unwrap(), chars);
+        }
+
+        let mut iter = unescape_indices("a\\uDE00b", &config);
+        assert!(matches!(iter.next(), Some(Ok((_, 'a')))));
+        assert!(matches!(iter.next(), Some(Err(_))));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_repair_escapes() {
+        #[track_caller]
+        fn check_repair(s: &str, expected: &str) {
+            let repaired = repair_escapes(s);
+            assert_eq!(expected, repaired);
+            assert!(safe_unescape_json_string(&repaired).is_ok());
+        }
+
+        // Valid escapes are untouched.
+        check_repair("a\\n\\\\\\\"\\/\\u00e9\\uD83D\\uDE00", "a\\n\\\\\\\"\\/\\u00e9\\uD83D\\uDE00");
+
+        // Unpaired surrogates
+        check_repair("a\\uD83Db", "a\u{FFFD}b");
+        check_repair("a\\uDE00b", "a\u{FFFD}b");
+        check_repair("\\uD83D\\uD83D\\uDE00", "\u{FFFD}\\uD83D\\uDE00");
+        check_repair("\\uD83D", "\u{FFFD}");
+
+        // Bad "\u" escapes
+        check_repair("ab\\u12", "abu12");
+        check_repair("\\u12G4x", "u12G4x");
+        check_repair("\\uD83D\\u12", "\u{FFFD}u12");
+
+        // Unknown escapes
+        check_repair("\\z\\q", "zq");
+        check_repair("\\x41", "x41");
+        check_repair("\\é", "é");
+        check_repair("abc\\", "abc");
+    }
+
+    #[test]
+    fn test_repair_and_verify() {
+        assert!(safe_unescape_json_string("lone \\uD83D surrogate").is_err());
+        assert_eq!(
+            ("lone \u{FFFD} surrogate".to_owned(), true),
+            repair_and_verify("lone \\uD83D surrogate")
+        );
+        assert_eq!(("truncated u00".to_owned(), true), repair_and_verify("truncated \\u00"));
+        assert_eq!(("ok\\n".to_owned(), true), repair_and_verify("ok\\n"));
+    }
+
+    #[test]
+    fn test_eager_surrogate_errors() {
+        let config = UnescapeConfig {
+            eager_surrogate_errors: true,
+            ..UnescapeConfig::default()
+        };
+
+        // The error is at char 8 (counting the opening quote as char 0),
+        // right after the "\uD83D", which spans chars 2 through 7.
+        for escaped in ["a\\uD83Dxyz", "a\\uD83D\\u0041", "a\\uD83D\\n", "a\\uD83D"] {
+            let err = unescape_json_string(escaped, &config).unwrap_err();
+            assert_eq!(8, err.index);
+            assert_eq!(1..7, err.byte_range());
+        }
+
+        // By default, the following escape is read first.
+        let err = safe_unescape_json_string("a\\uD83D\\u0041").unwrap_err();
+        assert_eq!(14, err.index);
+        assert_eq!(1..7, err.byte_range());
+    }
+
+    #[test]
+    fn test_escape_with_encoder() {
+        let code_points = |src: &str| {
+            escape_with_encoder(src, |escaped, c| {
+                write!(escaped, "{{U+{:04X}}}", c as u32).unwrap()
+            })
+        };
+
+        assert_eq!("", code_points(""));
+        assert_eq!("plain text, 100% safe!", code_points("plain text, 100% safe!"));
+        assert_eq!(
+            "{U+0022}a{U+005C}b{U+0027}{U+003C}{U+003E}{U+0026}",
+            code_points("\"a\\b'<>&")
+        );
+        assert_eq!("tab{U+0009}nl{U+000A}", code_points("tab\tnl\n"));
+        assert_eq!("caf{U+00E9} {U+1F600}", code_points("café 😀"));
+
+        // The encoder can reproduce the other escapers.
+        let html = escape_with_encoder("a<b> & é", |escaped, c| {
+            write!(escaped, "&#x{:x};", c as u32).unwrap()
+        });
+        assert_eq!(escape_html_numeric("a<b> & é"), html);
+    }
+
+    #[test]
+    fn test_escape_undisplayable() {
+        let bmp_only = |c: char| (c as u32) <= 0xFFFF;
+
+        assert_eq!("", escape_undisplayable("", &bmp_only));
+        assert_eq!("café 中文", escape_undisplayable("café 中文", &bmp_only));
+        assert_eq!(
+            "smile: \\ud83d\\ude00, \\ud801\\udc37!",
+            escape_undisplayable("smile: 😀, 𐐷!", &bmp_only)
+        );
+
+        // Quotes, backslashes and controls are escaped even if displayable.
+        assert_eq!(
+            "\\\"\\\\\\n\\t\\u001b\\u007f",
+            escape_undisplayable("\"\\\n\t\u{1b}\u{7f}", &|_| true)
+        );
+        assert_eq!("\\u0061b", escape_undisplayable("ab", &|c| c != 'a'));
+        assert!(is_valid_json_string_body(&escape_undisplayable(
+            "x😀\"\u{1b}é",
+            &bmp_only
+        )));
+    }
+
+    #[test]
+    fn test_prepare_query() {
+        let prepare = |query: &str, lowercase: bool, nfc: bool| {
+            prepare_query(query, &QueryConfig { lowercase, nfc }).unwrap()
+        };
+
+        assert_eq!("caf\\u00e9", prepare("caf\\u00e9", false, false));
+        assert_eq!("caf\\u00e9", prepare("café", false, false));
+        assert_eq!("caf\\u00e9", prepare("caf\\u00C9", true, false));
+        assert_eq!("a/b \\\"\\n", prepare("a\\/b \\\"\\n", false, false));
+
+        // A decomposed 'e' followed by U+0301 (combining acute accent).
+        let decomposed = "cafe\\u0301";
+        assert_eq!("cafe\\u0301", prepare(decomposed, false, false));
+        assert_eq!("caf\\u00e9", prepare(decomposed, false, true));
+        assert_eq!(prepare("CAF\\u00c9", true, true), prepare("CAFE\u{301}", true, true));
+
+        assert!(prepare_query("\\uD800", &QueryConfig::default()).is_err());
+        assert!(prepare_query("\\x", &QueryConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_escape_js_safe() {
+        assert_eq!("", escape_js_safe(""));
+        assert_eq!("ordinary text, café 中文 😀", escape_js_safe("ordinary text, café 中文 😀"));
+        assert_eq!("a\\u2028b\\u2029c", escape_js_safe("a\u{2028}b\u{2029}c"));
+        let bidi_controls: String =
+            ('\u{202a}'..='\u{202e}').chain('\u{2066}'..='\u{2069}').collect();
+        assert_eq!(
+            "\\u202a\\u202b\\u202c\\u202d\\u202e\\u2066\\u2067\\u2068\\u2069",
+            escape_js_safe(&bidi_controls)
+        );
+        assert_eq!("\\u003c/script>\\u003c!--", escape_js_safe("</script><!--"));
+        assert_eq!("\\\"\\\\\\n\\u001b", escape_js_safe("\"\\\n\u{1b}"));
+        assert!(is_valid_json_string_body(&escape_js_safe("x\u{2028}\"\u{202e}<")));
+    }
+
+    #[test]
+    fn test_contains_dangerous_sequences() {
+        assert!(contains_dangerous_sequences("abc \x1b[2J def"));
+        assert!(contains_dangerous_sequences("\x1b]0;title\x07"));
+        assert!(contains_dangerous_sequences("8-bit CSI: \u{9b}31m"));
+        assert!(contains_dangerous_sequences("8-bit OSC: \u{9d}52;c;Zm9v\u{9c}"));
+
+        assert!(!contains_dangerous_sequences("an ordinary value"));
+        assert!(!contains_dangerous_sequences("tabs\tand\nnewlines, 中文, 😀"));
+        // Escaped (i.e., not raw) ESCs are harmless.
+        assert!(!contains_dangerous_sequences("\\u001b[2J"));
+    }
+
+    #[test]
+    fn test_is_terminal_safe() {
+        assert!(is_terminal_safe(""));
+        assert!(is_terminal_safe("a plain value"));
+        assert!(is_terminal_safe("tabs\\tand\\nnewlines, \\u4e2d\\u6587, \\ud83d\\ude00"));
+        assert!(is_terminal_safe("\\u0007\\u0000\\u009c"));
+
+        assert!(!is_terminal_safe("red: \\u001b[31m"));
+        assert!(!is_terminal_safe("red: \\u001B[31m"));
+        assert!(!is_terminal_safe("red: \x1b[31m"));
+        assert!(!is_terminal_safe("8-bit OSC: \\u009d52;c;Zm9v\\u009c"));
+
+        // Invalid strings get printed as is.
+        assert!(is_terminal_safe("ok \\q"));
+        assert!(!is_terminal_safe("\x1b\\q"));
+        assert!(!is_terminal_safe("\\q\x1b"));
+    }
+
+    #[cfg(feature = "unicode-general-category")]
+    #[test]
+    fn test_escape_non_printable() {
+        assert!(is_non_printable('\u{200E}'));
+        assert!(is_non_printable('\u{0378}'));
+        assert!(is_non_printable('\u{E000}'));
+        assert!(is_non_printable('\x1b'));
+        assert!(!is_non_printable('a'));
+        assert!(!is_non_printable('\u{e9}'));
+
+        let mut config = EscapeConfig {
+            style: EscapeStyle::Minimal,
+            escape_non_printable: true,
+            ..EscapeConfig::default()
+        };
+        // U+0378 is unassigned, and U+E000 is for private use.
+        let src = "caf\u{e9}\u{200e} \u{4e2d}\u{0378}\u{e000}\x1b";
+        assert_eq!(
+            "caf\u{e9}\\u200e \u{4e2d}\\u0378\\ue000\\u001b",
+            escape_json_string_with(src, &config).unwrap()
+        );
+
+        config.escape_non_printable = false;
+        assert_eq!(
+            "caf\u{e9}\u{200e} \u{4e2d}\u{0378}\u{e000}\\u001b",
+            escape_json_string_with(src, &config).unwrap()
+        );
+    }
+
+    #[cfg(feature = "unicode-security")]
+    #[test]
+    fn test_detect_confusables() {
+        assert!(!detect_confusables("paypal"));
+        assert!(!detect_confusables("an ordinary value, 123!"));
+        assert!(!detect_confusables("привет мир"));
+        assert!(!detect_confusables("hello мир"));
+        assert!(!detect_confusables("日本語のテキスト"));
+
+        assert!(detect_confusables("\u{0440}\u{0430}ypal"));
+        assert!(detect_confusables("log in to p\u{0430}ypal.com"));
+    }
+
+    #[test]
+    fn test_escape_json_string_with_max_output_len() {
+        // Each emoji becomes a 12 byte surrogate pair escape.
+        let src = "😀😀😀";
+        assert_eq!(36, escape_json_string(src).len());
+
+        let mut config = EscapeConfig {
+            max_output_len: Some(30),
+            ..EscapeConfig::default()
+        };
+        let err = escape_json_string_with(src, &config).unwrap_err();
+        assert_eq!(8, err.index());
+        assert_eq!(30, err.limit());
+        assert_eq!(
+            "escaped output exceeds 30 bytes at byte 8 of input",
+            format!("{err}")
+        );
+
+        config.truncate_at_limit = true;
+        let escaped = escape_json_string_with(src, &config).unwrap();
+        assert_eq!("\\ud83d\\ude00\\ud83d\\ude00", escaped);
+        assert!(escaped.len() <= 30);
+
+        // Output exactly at the limit is fine.
+        config.max_output_len = Some(36);
+        config.truncate_at_limit = false;
+        assert_eq!(
+            escape_json_string(src),
+            escape_json_string_with(src, &config).unwrap()
+        );
+
+        // No limit by default.
+        assert_eq!(
+            escape_json_string(src),
+            escape_json_string_with(src, &EscapeConfig::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_escape_hex_case() {
+        #[track_caller]
+        fn check_case(src: &str, style: EscapeStyle, hex_case: HexCase, expected: &str) {
+            let config = EscapeConfig {
+                style,
+                hex_case,
+                ..EscapeConfig::default()
+            };
+            assert_eq!(expected, escape_json_string_with(src, &config).unwrap());
+        }
+
+        use EscapeStyle::*;
+        use HexCase::*;
+
+        check_case("😀\u{1b}", Json, Lower, "\\ud83d\\ude00\\u001b");
+        check_case("😀\u{1b}", Json, Upper, "\\uD83D\\uDE00\\u001B");
+        check_case("😀\u{1b}", CHex, Lower, "\\ud83d\\ude00\\x1b");
+        check_case("😀\u{1b}", CHex, Upper, "\\uD83D\\uDE00\\x1B");
+        check_case("😀\u{1b}", Json5, Lower, "\\u{1f600}\\u001b");
+        check_case("😀\u{1b}", Json5, Upper, "\\u{1F600}\\u001B");
+
+        // Lowercase by default, matching escape_json_string.
+        assert_eq!(
+            escape_json_string("😀\u{1b}"),
+            escape_json_string_with("😀\u{1b}", &EscapeConfig::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_escape_char() {
+        #[track_caller]
+        fn check_char(c: char, style: EscapeStyle, expected: &str) {
+            assert_eq!(expected, escape_char(c, style).as_str());
+            assert_eq!(expected, format!("{}", escape_char(c, style)));
+        }
+
+        use EscapeStyle::*;
+
+        for style in [Json, CHex, Json5, Minimal] {
+            check_char('"', style, "\\\"");
+            check_char('\\', style, "\\\\");
+            check_char('\n', style, "\\n");
+            check_char('a', style, "a");
+        }
+
+        check_char('\u{20ac}', Json, "\\u20ac");
+        check_char('\u{20ac}', Json5, "\\u20ac");
+        check_char('\u{20ac}', Minimal, "\u{20ac}");
+
+        check_char('😀', Json, "\\ud83d\\ude00");
+        check_char('😀', CHex, "\\ud83d\\ude00");
+        check_char('😀', Json5, "\\u{1f600}");
+        check_char('😀', Minimal, "😀");
+
+        check_char('\u{1b}', Json, "\\u001b");
+        check_char('\u{1b}', CHex, "\\x1b");
+        check_char('\u{1b}', Minimal, "\\u001b");
+        check_char('\u{85}', Minimal, "\\u0085");
+
+        // Matches escaping whole strings.
+        let src = "a\"\\\u{1b}€😀\u{85}";
+        for style in [Json, CHex, Json5, Minimal] {
+            let escaped: String = src.chars().map(|c| escape_char(c, style).to_string()).collect();
+            assert_eq!(escape_with_style(src, style), escaped);
+        }
+    }
+
+    #[test]
+    fn test_escaped_len_exact() {
+        use EscapeStyle::*;
+
+        assert_eq!(1, escaped_len_of_char('a', Json));
+        assert_eq!(2, escaped_len_of_char('\n', Json));
+        assert_eq!(6, escaped_len_of_char('\u{1b}', Json));
+        assert_eq!(4, escaped_len_of_char('\u{1b}', CHex));
+        assert_eq!(12, escaped_len_of_char('😀', Json));
+        assert_eq!(9, escaped_len_of_char('😀', Json5));
+        assert_eq!(4, escaped_len_of_char('😀', Minimal));
+
+        let mut rng = XorShift(0x5eed1e7);
+        let mut inputs = vec![
+            String::new(),
+            "plain ascii".to_owned(),
+            "\"quotes\" and \\backslashes\\".to_owned(),
+            "\u{0}\u{1b}\u{7f}\u{85}\t\r\n".to_owned(),
+            "€ 中 😀 𐐷".to_owned(),
+        ];
+        inputs.extend((0..100).map(|_| rng.string(20)));
+
+        for src in inputs.iter() {
+            assert_eq!(escape_json_string(src).len(), escaped_len_exact(src, Json));
+            for style in [Json, CHex, Json5, Minimal] {
+                assert_eq!(
+                    escape_with_style(src, style).len(),
+                    escaped_len_exact(src, style)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_escaped_byte_lengths() {
+        use EscapeStyle::*;
+
+        let unescaped = "a\u{1}\n\"é😀";
+        assert_eq!(vec![1, 6, 2, 2, 6, 12], escaped_byte_lengths(unescaped, Json));
+        assert_eq!(vec![1, 4, 2, 2, 6, 12], escaped_byte_lengths(unescaped, CHex));
+        assert_eq!(vec![1, 6, 2, 2, 6, 9], escaped_byte_lengths(unescaped, Json5));
+        assert_eq!(vec![1, 6, 2, 2, 2, 4], escaped_byte_lengths(unescaped, Minimal));
+        assert!(escaped_byte_lengths("", Json).is_empty());
+
+        let mut rng = XorShift(0xE7037ED1A0B428DB);
+        for _ in 0..200 {
+            let unescaped = rng.string(20);
+            let mut escaped_offset = 0;
+            let lengths = escaped_byte_lengths(&unescaped, Json);
+            let boundaries = char_boundaries(&unescaped);
+            for (&len, &(_, escaped_start)) in lengths.iter().zip(boundaries.iter()) {
+                assert_eq!(escaped_start, escaped_offset);
+                escaped_offset += len;
+            }
+            assert_eq!(escape_json_string(&unescaped).len(), escaped_offset);
+        }
+    }
+
+    #[test]
+    fn test_total_escaped_len() {
+        use EscapeStyle::*;
+
+        let inputs = ["plain", "", "\"quoted\"\n", "\u{1b}[0m", "中文 😀"];
+        for style in [Json, CHex, Json5, Minimal] {
+            let expected: usize = inputs.iter().map(|src| escape_with_style(src, style).len()).sum();
+            assert_eq!(expected, total_escaped_len(&inputs, style));
+        }
+        assert_eq!(0, total_escaped_len(&[], Json));
+    }
+
+    #[test]
+    fn test_escape_join() {
+        use EscapeStyle::*;
+
+        let parts = ["plain", "\"quoted\"\n", "中文 😀"];
+        assert_eq!(
+            "plain, \\\"quoted\\\"\\n, \\u4e2d\\u6587 \\ud83d\\ude00",
+            escape_join(&parts, ", ", Json)
+        );
+        for style in [Json, CHex, Json5, Minimal] {
+            let escaped: Vec<String> =
+                parts.iter().map(|part| escape_with_style(part, style)).collect();
+            let joined = escape_join(&parts, ", ", style);
+            assert_eq!(escaped.join(", "), joined);
+            assert_eq!(joined.len(), joined.capacity());
+        }
+
+        // The separator isn't escaped.
+        assert_eq!("a\n\"b", escape_join(&["a", "b"], "\n\"", Json));
+        assert_eq!("only", escape_join(&["only"], ", ", Json));
+        assert_eq!("", escape_join(&[], ", ", Json));
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_chars_fitting_in_bytes() {
+        use EscapeStyle::*;
 
-    #[track_caller]
-    fn check(escaped: &str, expected_unescaped: &str) {
-        let unescaped = match safe_unescape_json_string(escaped) {
-            Ok(s) => s,
-            Err(err) => format!("ERR: {err}"),
+        // "ab" is 2 bytes, the emoji is 12.
+        let src = "ab😀c";
+        assert_eq!(0, chars_fitting_in_bytes(src, 0, Json));
+        assert_eq!(2, chars_fitting_in_bytes(src, 2, Json));
+        assert_eq!(2, chars_fitting_in_bytes(src, 13, Json));
+        assert_eq!(3, chars_fitting_in_bytes(src, 14, Json));
+        assert_eq!(4, chars_fitting_in_bytes(src, 15, Json));
+        assert_eq!(4, chars_fitting_in_bytes(src, 100, Json));
+
+        assert_eq!(3, chars_fitting_in_bytes(src, 11, Json5));
+        assert_eq!(3, chars_fitting_in_bytes(src, 6, Minimal));
+
+        // Matches truncating escape_json_string_with at the same limit.
+        let mut rng = XorShift(0xc0ffee);
+        for _ in 0..100 {
+            let src = rng.string(20);
+            let max_bytes = rng.next() as usize % 64;
+            let config = EscapeConfig {
+                max_output_len: Some(max_bytes),
+                truncate_at_limit: true,
+                ..EscapeConfig::default()
+            };
+            let truncated = escape_json_string_with(&src, &config).unwrap();
+            let n = chars_fitting_in_bytes(&src, max_bytes, Json);
+            let prefix: String = src.chars().take(n).collect();
+            assert_eq!(escape_json_string(&prefix), truncated);
+        }
+    }
+
+    #[test]
+    fn test_escape_writer() {
+        use std::io::Write as _;
+
+        let src = "a\"€\n😀 \u{1b}中";
+        let bytes = src.as_bytes();
+
+        // Split at every possible pair of points, including in the middle
+        // of multi-byte chars.
+        for i in 0..=bytes.len() {
+            for j in i..=bytes.len() {
+                let mut writer = EscapeWriter::new(Vec::new());
+                writer.write_all(&bytes[..i]).unwrap();
+                writer.write_all(&bytes[i..j]).unwrap();
+                writer.write_all(&bytes[j..]).unwrap();
+                let escaped = writer.into_inner().unwrap();
+                assert_eq!(escape_json_string(src), String::from_utf8(escaped).unwrap());
+            }
+        }
+
+        // One byte at a time
+        let mut writer = EscapeWriter::new(Vec::new());
+        for byte in bytes {
+            writer.write_all(&[*byte]).unwrap();
+        }
+        assert_eq!(escape_json_string(src).as_bytes(), &writer.into_inner().unwrap()[..]);
+
+        let mut writer = EscapeWriter::new(Vec::new());
+        let key = "key";
+        let value = "😀";
+        write!(writer, "{}: {}", key, value).unwrap();
+        assert_eq!(b"key: \\ud83d\\ude00", &writer.into_inner().unwrap()[..]);
+
+        // Invalid and incomplete UTF-8
+        let mut writer = EscapeWriter::new(Vec::new());
+        assert!(writer.write_all(b"a\xffb").is_err());
+        let mut writer = EscapeWriter::new(Vec::new());
+        writer.write_all(&"😀".as_bytes()[..2]).unwrap();
+        assert!(writer.into_inner().is_err());
+    }
+
+    #[test]
+    fn test_escape_json_string_lenient() {
+        for src in ["", "abc", "a\"\n\u{1b}é😀"] {
+            assert_eq!(escape_json_string(src), escape_json_string_lenient(src.as_bytes()));
+        }
+
+        // A lone high surrogate (ED A0 80), a stray continuation byte, and
+        // a truncated sequence at the end.
+        let src = b"a\xED\xA0\x80b\x80\n\xF0\x9F";
+        assert_eq!("a\\ufffdb\\ufffd\\n\\ufffd", escape_json_string_lenient(src));
+    }
+
+    #[test]
+    fn test_escape_for_display() {
+        use WhitespaceRendering::*;
+
+        let src = "a\tb\n\"c\"\t\u{1b}";
+        assert_eq!(escape_json_string(src), escape_for_display(src, Escape));
+        assert_eq!("a\\tb\\n\\\"c\\\"\\t\\u001b", escape_for_display(src, Escape));
+        assert_eq!("a→b↵\\\"c\\\"→\\u001b", escape_for_display(src, Symbol));
+        assert_eq!(
+            "a   b\n\\\"c\\\"   \\u001b",
+            escape_for_display(src, Expand { tab_width: 4 })
+        );
+
+        // Tabs expand to the next tab stop.
+        assert_eq!("        x", escape_for_display("\tx", Expand { tab_width: 8 }));
+        assert_eq!("abcd    x", escape_for_display("abcd\tx", Expand { tab_width: 4 }));
+        assert_eq!("\\u00e9  x", escape_for_display("é\tx", Expand { tab_width: 4 }));
+        assert_eq!(" x", escape_for_display("\tx", Expand { tab_width: 0 }));
+
+        let log = "level:\t  warn \t\tmsg:  \"x\"\n";
+        assert_eq!(
+            "level:\t  warn \t\tmsg:  \\\"x\\\"\\n",
+            escape_for_display(log, Preserve)
+        );
+        assert_eq!(
+            "level:\\t  warn \\t\\tmsg:  \\\"x\\\"\\n",
+            escape_for_display(log, Escape)
+        );
+        // Only ASCII spaces and tabs are preserved.
+        assert_eq!("\t \\u3000", escape_for_display("\t \u{3000}", Preserve));
+    }
+
+    #[test]
+    fn test_to_control_pictures() {
+        assert_eq!("", to_control_pictures(""));
+        assert_eq!("plain text, 中文 😀", to_control_pictures("plain text, 中文 😀"));
+        assert_eq!("␀a␉b␊c␍␛[0m␟␡", to_control_pictures("\0a\tb\nc\r\x1b[0m\x1f\x7f"));
+        assert_eq!("\\u009b31m\\u0085", to_control_pictures("\u{9b}31m\u{85}"));
+        assert!(!contains_dangerous_sequences(&to_control_pictures("\x1b]0;\u{9d}")));
+    }
+
+    #[test]
+    fn test_escape_lines() {
+        assert_eq!(vec!["a\\\"b", "c\\td"], escape_lines("a\"b\nc\td"));
+        assert_eq!(vec!["a", "b", ""], escape_lines("a\r\nb\n"));
+        assert_eq!(vec!["a\\rb"], escape_lines("a\rb"));
+        assert_eq!(vec!["", "\\ud83d\\ude00"], escape_lines("\n😀"));
+        assert_eq!(vec![""], escape_lines(""));
+    }
+
+    #[test]
+    fn test_escape_html_numeric() {
+        assert_eq!(
+            "&#x3c;a&#x3e; &#x26; &#x22;b&#x22; &#x27;c&#x27;",
+            escape_html_numeric("<a> & \"b\" 'c'")
+        );
+        assert_eq!("plain text, 1 + 2 = 3!", escape_html_numeric("plain text, 1 + 2 = 3!"));
+        assert_eq!("caf&#xe9; &#x20ac;&#x1f600;", escape_html_numeric("café €😀"));
+        assert_eq!("a&#xa;&#x1b;&#x7f;\\", escape_html_numeric("a\n\u{1b}\u{7f}\\"));
+        assert_eq!("", escape_html_numeric(""));
+    }
+
+    #[test]
+    fn test_escape_del() {
+        use EscapeStyle::*;
+
+        for style in [Json, CHex, Json5, Minimal] {
+            let mut config = EscapeConfig {
+                style,
+                ..EscapeConfig::default()
+            };
+            assert_eq!("a\\u007fb", escape_json_string_with("a\x7fb", &config).unwrap());
+            config.pass_through_del = true;
+            assert_eq!("a\x7fb", escape_json_string_with("a\x7fb", &config).unwrap());
+        }
+        assert_eq!("\\u007f", escape_json_string("\x7f"));
+    }
+
+    #[test]
+    fn test_c_hex_escape_style() {
+        let src = "a\x00b\x1b[0m\t\n\x7f \"€\"";
+        let escaped = "a\\x00b\\x1b[0m\\t\\n\\u007f \\\"\\u20ac\\\"";
+
+        let escape_config = EscapeConfig {
+            style: EscapeStyle::CHex,
+            ..EscapeConfig::default()
         };
+        assert_eq!(escaped, escape_json_string_with(src, &escape_config).unwrap());
 
-        assert_eq!(expected_unescaped, &unescaped);
+        let mut unescape_config = UnescapeConfig {
+            escape_control_characters: false,
+            style: EscapeStyle::CHex,
+            ..UnescapeConfig::default()
+        };
+        assert_eq!(src, unescape_json_string(escaped, &unescape_config).unwrap());
+
+        // Control characters are left escaped the way they were written.
+        unescape_config.escape_control_characters = true;
+        assert_eq!(
+            "a\\x00b\\x1b[0m\t\n\\u007f \"€\"",
+            unescape_json_string(escaped, &unescape_config).unwrap()
+        );
+
+        // Non-control bytes are decoded as Latin-1.
+        assert_eq!("Aé", unescape_json_string("\\x41\\xe9", &unescape_config).unwrap());
     }
 
-    #[track_caller]
-    fn check_unsafe(escaped: &str, expected_unescaped: &str) {
-        let unescaped = match unsafe_unescape_json_string(escaped) {
-            Ok(s) => s,
-            Err(err) => format!("ERR: {err}"),
+    #[test]
+    fn test_unescape_error_codepoint() {
+        let err = safe_unescape_json_string("ab \\uDC37").unwrap_err();
+        assert_eq!(Some(0xDC37), err.codepoint());
+        assert_eq!(None, err.as_char());
+
+        let err = safe_unescape_json_string("\\ud801 ").unwrap_err();
+        assert_eq!(Some(0xD801), err.codepoint());
+        assert_eq!(None, err.as_char());
+
+        let err = safe_unescape_json_string("\\q").unwrap_err();
+        assert_eq!(None, err.codepoint());
+        assert_eq!(None, err.as_char());
+    }
+
+    #[test]
+    fn test_unescape_error_escape_text() {
+        let err = safe_unescape_json_string("\\uD801").unwrap_err();
+        assert_eq!(Some("D801"), err.escape_text());
+        let err = safe_unescape_json_string("a\\udc37").unwrap_err();
+        assert_eq!(Some("dc37"), err.escape_text());
+        let err = safe_unescape_json_string("\\q").unwrap_err();
+        assert_eq!(None, err.escape_text());
+    }
+
+    #[test]
+    fn test_lenient_esc_escape() {
+        let mut config = UnescapeConfig {
+            lenient: true,
+            ..UnescapeConfig::default()
         };
+        assert_eq!("a\\u001b[0m", unescape_json_string("a\\e[0m", &config).unwrap());
+        config.escape_control_characters = false;
+        assert_eq!("a\x1b[0m", unescape_json_string("a\\e[0m", &config).unwrap());
+        // Other unknown escapes are still errors.
+        assert!(unescape_json_string("\\q", &config).is_err());
 
-        assert_eq!(expected_unescaped, &unescaped);
+        check("a\\e[0m", "ERR: unescaping error at char 2: invalid escape sequence \"\\e\"");
+        check_unsafe("\\e", "ERR: unescaping error at char 1: invalid escape sequence \"\\e\"");
     }
 
     #[test]
-    fn test_unescape_json_string() {
-        // Ok
-        check("abc", "abc");
-        check("abc \\\\ \\\"", "abc \\ \"");
-        check("abc \\n \\t \\r", "abc \n \t \r");
-        check("€ \\u20AC", "€ \u{20AC}");
-        check("𐐷 \\uD801\\uDC37", "𐐷 \u{10437}");
+    fn test_named_control_rendering() {
+        let config = UnescapeConfig {
+            control_rendering: ControlRendering::Named,
+            ..UnescapeConfig::default()
+        };
+        #[track_caller]
+        fn check_named(config: &UnescapeConfig, escaped: &str, expected: &str) {
+            assert_eq!(expected, unescape_json_string(escaped, config).unwrap());
+        }
 
-        // Control characters are escaped
-        check("12x\\b34", "12x\\b34");
-        check(
-            "\\u0000 | \\u001f | \\u0020 | \\u007e | \\u007f | \\u0080 | \\u009F | \\u00a0",
-            "\\u0000 | \\u001f | \u{0020} | \u{007e} | \\u007f | \\u0080 | \\u009F | \u{00a0}",
+        check_named(&config, "\\u0000", "<NUL>");
+        check_named(&config, "a\\u001b[0mb", "a<ESC>[0mb");
+        check_named(&config, "\\u0085", "<U+0085>");
+        check_named(&config, "\\b\\u007f\\u001f", "<BS><DEL><US>");
+        // Raw control characters are named too.
+        check_named(&config, "\x00\x1b\u{85}", "<NUL><ESC><U+0085>");
+        // Short escapes that are always unescaped are unaffected.
+        check_named(&config, "\\n\\t", "\n\t");
+
+        // Has no effect when control characters aren't escaped.
+        let config = UnescapeConfig {
+            escape_control_characters: false,
+            ..config
+        };
+        check_named(&config, "\\u0000\\u001b", "\x00\x1b");
+    }
+
+    #[test]
+    fn test_bom_policy() {
+        #[track_caller]
+        fn check_bom(escaped: &str, bom_policy: BomPolicy, expected: &str) {
+            let config = UnescapeConfig {
+                bom_policy,
+                ..UnescapeConfig::default()
+            };
+            assert_eq!(expected, unescape_json_string(escaped, &config).unwrap());
+        }
+
+        for escaped in ["\u{FEFF}abc", "\\ufeffabc", "\\uFEFFabc"] {
+            check_bom(escaped, BomPolicy::Keep, "\u{FEFF}abc");
+            check_bom(escaped, BomPolicy::Strip, "abc");
+            check_bom(escaped, BomPolicy::Escape, "\\ufeffabc");
+        }
+
+        // Only a leading BOM is affected.
+        check_bom("a\u{FEFF}bc", BomPolicy::Strip, "a\u{FEFF}bc");
+        check_bom("a\u{FEFF}bc", BomPolicy::Escape, "a\u{FEFF}bc");
+        check_bom("\u{FEFF}\u{FEFF}", BomPolicy::Strip, "\u{FEFF}");
+    }
+
+    #[test]
+    fn test_transcode_escape() {
+        use EscapeStyle::*;
+
+        #[track_caller]
+        fn check_transcode(src: &str, from: EscapeStyle, to: EscapeStyle, expected: &str) {
+            assert_eq!(expected, transcode_escape(src, from, to).unwrap());
+        }
+
+        check_transcode("\\uD83D\\uDE00", Json, Json5, "\\u{1f600}");
+        check_transcode("\\u{1F600}", Json5, Json, "\\ud83d\\ude00");
+        check_transcode("\\u{1f600}", Json5, Json, "\\ud83d\\ude00");
+        check_transcode(
+            "a\\tb \\uD801\\uDC37 \\u20ac \\u0000",
+            Json,
+            Json5,
+            "a\\tb \\u{10437} \\u20ac \\u0000",
         );
+        check_transcode("\\x41\\u{1b}\\u{e9}", Json5, Json, "A\\u001b\\u00e9");
+        check_transcode("\\x1b\\u001b", CHex, Json5, "\\u001b\\u001b");
 
-        // But not if unsafe is called
-        check_unsafe("12x\\b34", "12x\x0834");
-        check_unsafe(
-            "\\u0000 | \\u001f | \\u0020 | \\u007e | \\u007f | \\u0080 | \\u009F | \\u00a0",
-            "\x00 | \x1f | \u{0020} | \u{007e} | \x7f | \u{0080} | \u{009F} | \u{00a0}",
+        // Round trip.
+        let json = "\\ud83d\\ude00 \\\" \\\\";
+        let json5 = transcode_escape(json, Json, Json5).unwrap();
+        assert_eq!(json, transcode_escape(&json5, Json5, Json).unwrap());
+
+        // Unescaping errors are passed through.
+        assert!(transcode_escape("\\uDE00", Json, Json5).is_err());
+    }
+
+    #[test]
+    fn test_json5_invalid_code_point_escape() {
+        use std::io::Write as _;
+
+        let config = UnescapeConfig {
+            style: EscapeStyle::Json5,
+            ..UnescapeConfig::default()
+        };
+        #[track_caller]
+        fn check_invalid(escaped: &str, expected_escape: &str, expected_span: Range<usize>) {
+            let config = UnescapeConfig {
+                style: EscapeStyle::Json5,
+                ..UnescapeConfig::default()
+            };
+            let err = unescape_json_string(escaped, &config).unwrap_err();
+            match &err.error {
+                UnescapeErrorKind::InvalidHexEscape(escape) => assert_eq!(expected_escape, escape),
+                _ => panic!("unexpected error for {:?}: {}", escaped, err),
+            }
+            assert_eq!(expected_span, err.byte_range());
+        }
+
+        // Unclosed
+        check_invalid("a\\u{12", "\\u{12", 1..6);
+        check_invalid("a\\u{12 b}", "\\u{12 ", 1..7);
+        // No digits
+        check_invalid("\\u{}", "\\u{}", 0..4);
+        // Not hex
+        check_invalid("\\u{zz}", "\\u{z", 0..4);
+        // Too many digits
+        check_invalid("\\u{0000041}", "\\u{0000041", 0..10);
+        // Not a char
+        check_invalid("\\u{110000}", "\\u{110000}", 0..10);
+        check_invalid("\\u{d800}", "\\u{d800}", 0..8);
+
+        assert_eq!("\u{10FFFF}A", unescape_json_string("\\u{10ffff}\\u{41}", &config).unwrap());
+        assert!(transcode_escape("\\u{zz}", EscapeStyle::Json5, EscapeStyle::Json).is_err());
+        assert!(unescape_lines("a\\u{12\n".as_bytes(), Vec::new(), &config).is_err());
+
+        // A "\u{" is only held back while it could still be completed.
+        assert_eq!(1, complete_prefix_len("a\\u{12", EscapeStyle::Json5));
+        assert_eq!(9, complete_prefix_len("a\\u{12 b}", EscapeStyle::Json5));
+        assert_eq!(10, complete_prefix_len("\\u{1234567", EscapeStyle::Json5));
+        let mut writer = UnescapeWriter::new(Vec::new(), &config);
+        writer.write_all(b"a\\u{12").unwrap();
+        assert!(writer.write_all(b"x").is_err());
+        let mut writer = UnescapeWriter::new(Vec::new(), &config);
+        writer.write_all(b"a\\u{12").unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn test_canonicalize_escapes() {
+        #[track_caller]
+        fn check_canonical(s: &str, style: EscapeStyle, expected: &str) {
+            let canonical = canonicalize_escapes(s, style).unwrap();
+            assert_eq!(expected, canonical);
+            // Canonicalizing is idempotent.
+            assert_eq!(canonical, canonicalize_escapes(&canonical, style).unwrap());
+            assert!(is_canonically_escaped(&canonical, style));
+        }
+
+        use EscapeStyle::*;
+
+        check_canonical("\\u0041", Json, "A");
+        check_canonical("\\/ \\u00e9 \\uD83D\\uDE00", Json, "/ \\u00e9 \\ud83d\\ude00");
+        check_canonical("\\/ \\u00e9 \\uD83D\\uDE00", Minimal, "/ é 😀");
+        check_canonical("é", Json, "\\u00e9");
+        check_canonical("a\\nb\\t", Json, "a\\nb\\t");
+        check_canonical("\\u001B\\u0085", Json, "\\u001b\\u0085");
+        check_canonical("\\\" \\\\ \\u0022", Json, "\\\" \\\\ \\\"");
+        check_canonical("\\u001b\\x41", CHex, "\\x1bA");
+        check_canonical("\\u{1F600}\\u{1b}", Json5, "\\u{1f600}\\u001b");
+
+        assert!(canonicalize_escapes("\\uDE00", Json).is_err());
+    }
+
+    #[test]
+    fn test_first_noncanonical() {
+        use EscapeStyle::*;
+
+        assert_eq!(None, first_noncanonical("", Json));
+        assert_eq!(None, first_noncanonical("abc \\\" \\n \\u00e9 \\ud801\\udc37", Json));
+        assert_eq!(Some(0), first_noncanonical("\\u0041", Json));
+        assert_eq!(Some(3), first_noncanonical("abc\\u0041\\/", Json));
+        assert_eq!(Some(2), first_noncanonical("\\n\\u00E9", Json));
+        assert_eq!(Some(1), first_noncanonical("a\\uD801\\uDC37", Json));
+        assert_eq!(Some(1), first_noncanonical("a\nb", Json));
+        assert_eq!(Some(1), first_noncanonical("aé", Json));
+        assert_eq!(None, first_noncanonical("aé", Minimal));
+        // Invalid escapes.
+        assert_eq!(Some(2), first_noncanonical("ab\\ud801", Json));
+        assert_eq!(Some(1), first_noncanonical("a\\", Json));
+        assert_eq!(Some(0), first_noncanonical("\\u001b", CHex));
+        assert_eq!(Some(1), first_noncanonical("a\\u{110000}", Json5));
+        assert_eq!(Some(1), first_noncanonical("a\\u{41", Json5));
+        // Only the style's own escapes are decoded, never lenient ones.
+        assert_eq!(Some(1), first_noncanonical("a\\e", Json));
+    }
+
+    #[test]
+    fn test_is_canonically_escaped() {
+        use EscapeStyle::*;
+
+        assert!(is_canonically_escaped("", Json));
+        assert!(is_canonically_escaped("abc \\\" \\\\ \\n \\u00e9 \\ud801\\udc37", Json));
+        // Uppercase hex isn't canonical.
+        assert!(!is_canonically_escaped("\\u00E9", Json));
+        assert!(!is_canonically_escaped("\\uD801\\uDC37", Json));
+        // Unnecessary escapes aren't canonical.
+        assert!(!is_canonically_escaped("\\u0041", Json));
+        assert!(!is_canonically_escaped("\\/", Json));
+        assert!(!is_canonically_escaped("\\u000a", Json));
+        // Raw characters that should be escaped aren't canonical.
+        assert!(!is_canonically_escaped("\n", Json));
+        assert!(!is_canonically_escaped("é", Json));
+        // Invalid escapes aren't canonical.
+        assert!(!is_canonically_escaped("\\ud801", Json));
+        assert!(!is_canonically_escaped("\\", Json));
+
+        assert!(is_canonically_escaped("\\x1b\\n", CHex));
+        assert!(!is_canonically_escaped("\\u001b\\n", CHex));
+        assert!(is_canonically_escaped("\\u{10437}", Json5));
+        assert!(!is_canonically_escaped("\\ud801\\udc37", Json5));
+
+        let mut rng = XorShift(0x9E3779B97F4A7C15);
+        for _ in 0..500 {
+            let escaped = escape_json_string(&rng.string(10));
+            assert!(is_canonically_escaped(&escaped, Json), "{:?}", escaped);
+        }
+    }
+
+    #[test]
+    fn test_escape_json_string_is_valid_json() {
+        #[track_caller]
+        fn check_valid(src: &str) {
+            let escaped = escape_json_string(src);
+            assert!(
+                is_valid_json_string_body(&escaped),
+                "invalid escaped output {:?} for {:?}",
+                escaped,
+                src
+            );
+            assert_eq!(src, unsafe_unescape_json_string(&escaped).unwrap());
+        }
+
+        // Regression inputs
+        check_valid("");
+        check_valid("\\");
+        check_valid("\\n");
+        check_valid("\\\\\\");
+        check_valid("\\\"");
+        check_valid("\"quoted\" \\u0041");
+        check_valid("\x00 \x08 \x0c \n \r \t \x1f \x7f \u{80} \u{9f}");
+        check_valid("😀 𐐷 \u{10000} \u{10ffff} \\😀");
+
+        let mut rng = XorShift(0x2545F4914F6CDD1D);
+        for _ in 0..2000 {
+            check_valid(&rng.string(20));
+        }
+
+        assert!(!is_valid_json_string_body("\""));
+        assert!(!is_valid_json_string_body("\\"));
+        assert!(!is_valid_json_string_body("\\x41"));
+        assert!(!is_valid_json_string_body("\\u12"));
+        assert!(!is_valid_json_string_body("\n"));
+    }
+
+    #[test]
+    fn test_char_boundaries() {
+        assert_eq!(vec![(0, 0)], char_boundaries(""));
+        assert_eq!(
+            vec![(0, 0), (1, 1), (2, 3), (4, 9), (8, 21), (9, 27)],
+            // 'a' is 1 byte, '\n' is escaped to 2, 'é' is 2 bytes escaped
+            // to 6, '😀' is 4 bytes escaped to 12, and '\x00' to 6.
+            char_boundaries("a\né😀\x00")
         );
 
-        // Non-ASCII unescaped control codes also get escaped
-        check("12 \u{0080} 34", "12 \\u0080 34");
+        let mut rng = XorShift(0xD1B54A32D192ED03);
+        for _ in 0..500 {
+            let unescaped = rng.string(20);
+            let boundaries = char_boundaries(&unescaped);
+            assert_eq!(unescaped.chars().count() + 1, boundaries.len());
+            for pair in boundaries.windows(2) {
+                assert!(pair[0].0 < pair[1].0 && pair[0].1 < pair[1].1);
+            }
+            assert_eq!(
+                (unescaped.len(), escape_json_string(&unescaped).len()),
+                *boundaries.last().unwrap()
+            );
+        }
+    }
 
-        // But not if unsafe is called
-        check_unsafe("12 \u{0080} 34", "12 \u{0080} 34");
+    #[test]
+    fn test_edit_map() {
+        assert_eq!(Vec::<EditSpan>::new(), edit_map(""));
 
-        // Errors; make sure index is computed properly.
-        check(
-            "abc 𐐷 \\uD801\\uDC37 \\uD801",
-            // "abc 𐐷 \uD801\uDC37 \uD801"
-            // 0 2 4 6 8 0 2 4 6 8 0 2 4 6
-            "ERR: unescaping error at char 26: high surrogate \"\\uD801\" not followed by low surrogate",
+        let span = |unescaped: Range<usize>, escaped: Range<usize>, transformed: bool| {
+            EditSpan { unescaped, escaped, transformed }
+        };
+        // Escaped: a \n\ud801\udc37\"
+        assert_eq!(
+            vec![
+                span(0..1, 0..1, false),
+                span(1..2, 1..2, false),
+                span(2..3, 2..4, true),
+                span(3..7, 4..16, true),
+                span(7..8, 16..18, true),
+            ],
+            edit_map("a \n𐐷\"")
         );
 
-        check(
-            "abc 𐐷 \\uD801\\uDC37 \\uDC37",
-            // "abc 𐐷 \uD801\uDC37 \uDC37"
-            // 0 2 4 6 8 0 2 4 6 8 0 2 4 6
-            "ERR: unescaping error at char 20: unexpected low surrogate \"\\uDC37\"",
+        let mut rng = XorShift(0x9E3779B97F4A7C15);
+        for _ in 0..200 {
+            let unescaped = rng.string(20);
+            let escaped = escape_json_string(&unescaped);
+            let spans = edit_map(&unescaped);
+            let boundaries = char_boundaries(&unescaped);
+            assert_eq!(boundaries.len(), spans.len() + 1);
+            for (span, pair) in spans.iter().zip(boundaries.windows(2)) {
+                assert_eq!(pair[0].0..pair[1].0, span.unescaped);
+                assert_eq!(pair[0].1..pair[1].1, span.escaped);
+                assert_eq!(
+                    span.transformed,
+                    unescaped[span.unescaped.clone()] != escaped[span.escaped.clone()]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_char_index_of_escaped_byte() {
+        // Escaped: a\u00e9\nb\ud83d\ude00c
+        let unescaped = "aé\nb😀c";
+        assert_eq!(0, char_index_of_escaped_byte(unescaped, 0));
+        // Anywhere inside the \uXXXX escape for 'é'.
+        for escaped_byte in 1..7 {
+            assert_eq!(1, char_index_of_escaped_byte(unescaped, escaped_byte));
+        }
+        assert_eq!(2, char_index_of_escaped_byte(unescaped, 8));
+        assert_eq!(3, char_index_of_escaped_byte(unescaped, 9));
+        // Anywhere inside the surrogate pair.
+        assert_eq!(4, char_index_of_escaped_byte(unescaped, 10));
+        assert_eq!(4, char_index_of_escaped_byte(unescaped, 21));
+        assert_eq!(5, char_index_of_escaped_byte(unescaped, 22));
+        // Past the end.
+        assert_eq!(6, char_index_of_escaped_byte(unescaped, 23));
+        assert_eq!(6, char_index_of_escaped_byte(unescaped, 100));
+    }
+
+    #[test]
+    fn test_char_at_escaped_byte() {
+        // Escaped: a\u00e9\nb\ud801\udc37c
+        let unescaped = "aé\nb𐐷c";
+        assert_eq!(Some('a'), char_at_escaped_byte(unescaped, 0));
+        assert_eq!(Some('é'), char_at_escaped_byte(unescaped, 1));
+        assert_eq!(Some('é'), char_at_escaped_byte(unescaped, 6));
+        assert_eq!(Some('\n'), char_at_escaped_byte(unescaped, 8));
+        assert_eq!(Some('b'), char_at_escaped_byte(unescaped, 9));
+        // Anywhere inside the surrogate pair, including the low surrogate.
+        for escaped_byte in 10..22 {
+            assert_eq!(Some('𐐷'), char_at_escaped_byte(unescaped, escaped_byte));
+        }
+        assert_eq!(Some('c'), char_at_escaped_byte(unescaped, 22));
+        // Past the end.
+        assert_eq!(None, char_at_escaped_byte(unescaped, 23));
+        assert_eq!(None, char_at_escaped_byte("", 0));
+    }
+
+    #[test]
+    fn test_escape_unicode_for_regex() {
+        assert_eq!("abc.*", escape_unicode_for_regex("abc.*", true));
+        assert_eq!("a\\u00e9b", escape_unicode_for_regex("aéb", false));
+        assert_eq!("a\\\\u00e9b", escape_unicode_for_regex("aéb", true));
+        assert_eq!("\\ud83d\\ude00", escape_unicode_for_regex("😀", false));
+        assert_eq!("\\\\ud83d\\\\ude00", escape_unicode_for_regex("😀", true));
+
+        // ASCII input is borrowed.
+        assert!(matches!(escape_unicode_for_regex("abc.*", true), Cow::Borrowed("abc.*")));
+        assert!(matches!(escape_unicode_for_regex("", false), Cow::Borrowed("")));
+        assert!(matches!(escape_unicode_for_regex("aé", false), Cow::Owned(_)));
+    }
+
+    #[test]
+    fn test_escape_with_allowed() {
+        let alphanumeric = |c: char| c.is_ascii_alphanumeric();
+        assert_eq!("abc123", escape_with_allowed("abc123", &alphanumeric));
+        assert_eq!(
+            "a\\u0020b\\u002dc\\u00e9\\ud83d\\ude00\\u000a",
+            escape_with_allowed("a b-cé😀\n", &alphanumeric)
+        );
+
+        // Quotes and backslashes are always escaped.
+        let everything = |_: char| true;
+        assert_eq!("\\\"é\\\\", escape_with_allowed("\"é\\", &everything));
+        assert_eq!("\\\"\\\\", escape_with_allowed("\"\\", &alphanumeric));
+
+        // Allowing only ASCII graphic characters and spaces matches the
+        // default escaping of non-ASCII characters.
+        let ascii = |c: char| c.is_ascii_graphic() || c == ' ';
+        assert_eq!(escape_json_string("a é 中"), escape_with_allowed("a é 中", &ascii));
+    }
+
+    #[test]
+    fn test_build_literal_regex() {
+        let pattern = build_literal_regex("a.b*é");
+        assert_eq!("a\\.b\\*\\\\u00e9", pattern);
+
+        let regex = regex::Regex::new(&pattern).unwrap();
+        assert!(regex.is_match("\"xa.b*\\u00e9x\""));
+        assert!(!regex.is_match("\"axbb\\u00e9\""));
+        assert!(!regex.is_match("\"a.b*u00e9\""));
+
+        let regex = regex::Regex::new(&build_literal_regex("say \"hi\"\n(\\)")).unwrap();
+        assert!(regex.is_match("\"say \\\"hi\\\"\\n(\\\\)\""));
+    }
+
+    #[test]
+    fn test_find_range_from_escaped() {
+        #[track_caller]
+        fn check_range(
+            unescaped: &str,
+            escaped_range: Range<usize>,
+            expected: (Range<usize>, Range<usize>),
+        ) {
+            assert_eq!(expected, find_range_reference(unescaped, escaped_range.clone()));
+            assert_eq!(expected, find_range_from_escaped(unescaped, escaped_range));
+        }
+
+        check_range("abc", 0..3, (0..3, 0..3));
+        check_range("abc", 1..2, (1..2, 1..2));
+        check_range("abc", 1..1, (1..1, 1..1));
+        check_range("abc", 3..3, (3..3, 3..3));
+        check_range("abc", 5..7, (3..3, 3..3));
+        // Escaped: a\u00e9\nb
+        check_range("aé\nb", 0..1, (0..1, 0..1));
+        check_range("aé\nb", 0..2, (0..3, 0..7));
+        check_range("aé\nb", 3..5, (1..3, 1..7));
+        check_range("aé\nb", 3..8, (1..4, 1..9));
+        check_range("aé\nb", 7..10, (3..5, 7..10));
+        // Escaped: \ud83d\ude00!
+        check_range("😀!", 6..7, (0..4, 0..12));
+        check_range("😀!", 12..13, (4..5, 12..13));
+    }
+
+    #[test]
+    fn test_find_range_from_escaped_matches_reference() {
+        let mut rng = XorShift(0xBF58476D1CE4E5B9);
+        for _ in 0..2000 {
+            let unescaped = rng.string(12);
+            let escaped_len = escape_json_string(&unescaped).len();
+            let a = rng.next() as usize % (escaped_len + 3);
+            let b = rng.next() as usize % (escaped_len + 3);
+            let escaped_range = a.min(b)..a.max(b);
+
+            assert_eq!(
+                find_range_reference(&unescaped, escaped_range.clone()),
+                find_range_from_escaped(&unescaped, escaped_range.clone()),
+                "{:?} {:?}",
+                unescaped,
+                escaped_range,
+            );
+        }
+    }
+
+    #[test]
+    fn test_indexed_string() {
+        let unescaped = "aé\n😀\"b";
+        let index = IndexedString::new(unescaped);
+        assert_eq!(escape_json_string(unescaped), index.escaped());
+
+        // Escaped: a\u00e9\n\ud83d\ude00\"b
+        assert_eq!((1..3, 1..7), index.unescaped_to_escaped(1..3));
+        assert_eq!((1..3, 1..7), index.unescaped_to_escaped(2..3));
+        assert_eq!((3..8, 7..21), index.unescaped_to_escaped(3..5));
+        assert_eq!((9..10, 23..24), index.unescaped_to_escaped(9..12));
+        assert_eq!((10..10, 24..24), index.unescaped_to_escaped(12..14));
+        assert_eq!((3..8, 7..21), index.escaped_to_unescaped(8..12));
+        assert_eq!((4..4, 9..9), index.escaped_to_unescaped(9..9));
+
+        let empty = IndexedString::new("");
+        assert_eq!((0..0, 0..0), empty.unescaped_to_escaped(0..3));
+        assert_eq!((0..0, 0..0), empty.escaped_to_unescaped(2..3));
+
+        let mut rng = XorShift(0x94D049BB133111EB);
+        for _ in 0..500 {
+            let unescaped = rng.string(12);
+            let index = IndexedString::new(&unescaped);
+            let mut range = |len: usize| {
+                let a = rng.next() as usize % (len + 3);
+                let b = rng.next() as usize % (len + 3);
+                a.min(b)..a.max(b)
+            };
+
+            let escaped_range = range(index.escaped().len());
+            assert_eq!(
+                find_range_reference(&unescaped, escaped_range.clone()),
+                index.escaped_to_unescaped(escaped_range)
+            );
+
+            // Brute force the other direction by escaping the string up to
+            // the boundaries of the overlapped chars.
+            let unescaped_range = range(unescaped.len());
+            let boundaries: Vec<usize> = unescaped
+                .char_indices()
+                .map(|(offset, _)| offset)
+                .chain(std::iter::once(unescaped.len()))
+                .collect();
+            let start = boundaries
+                .windows(2)
+                .find(|pair| pair[1] > unescaped_range.start)
+                .map_or(unescaped.len(), |pair| pair[0]);
+            let end = boundaries
+                .iter()
+                .copied()
+                .find(|&offset| offset >= unescaped_range.end)
+                .unwrap_or(unescaped.len())
+                .max(start);
+            let escaped_offset = |offset: usize| escape_json_string(&unescaped[..offset]).len();
+            assert_eq!(
+                (start..end, escaped_offset(start)..escaped_offset(end)),
+                index.unescaped_to_escaped(unescaped_range)
+            );
+        }
+    }
+
+    #[test]
+    fn test_escape_chunks() {
+        let src = "a\"é\n😀";
+        let chunks: Vec<_> = escape_chunks(src).collect();
+        assert_eq!(
+            vec![
+                (0..1, Cow::Borrowed("a")),
+                (1..2, Cow::Owned("\\\"".to_owned())),
+                (2..4, Cow::Owned("\\u00e9".to_owned())),
+                (4..5, Cow::Owned("\\n".to_owned())),
+                (5..9, Cow::Owned("\\ud83d\\ude00".to_owned())),
+            ],
+            chunks
         );
+        assert!(matches!(chunks[0].1, Cow::Borrowed(_)));
+
+        let mut rng = XorShift(0x94D049BB133111EB);
+        for _ in 0..500 {
+            let src = rng.string(20);
+            let forward: Vec<_> = escape_chunks(&src).collect();
+            let mut reversed: Vec<_> = escape_chunks_rev(&src).collect();
+            reversed.reverse();
+            assert_eq!(forward, reversed);
+
+            let escaped: String = forward.into_iter().map(|(_, chunk)| chunk).collect();
+            assert_eq!(escape_json_string(&src), escaped);
+        }
+    }
+
+    #[test]
+    fn test_merge_ranges() {
+        #[track_caller]
+        fn assert_merged(mut ranges: Vec<Range<usize>>, expected: Vec<Range<usize>>) {
+            merge_ranges(&mut ranges);
+            assert_eq!(expected, ranges);
+        }
+
+        assert_merged(vec![0..3, 3..5, 7..9], vec![0..5, 7..9]);
+        assert_merged(vec![0..5, 7..9], vec![0..5, 7..9]);
+        assert_merged(vec![], vec![]);
+        // Overlapping, contained, and out of order.
+        assert_merged(vec![7..9, 2..6, 0..3, 3..4], vec![0..6, 7..9]);
+        assert_merged(vec![8..9, 12..13, 1..10], vec![1..10, 12..13]);
+    }
+
+    #[test]
+    fn test_escaped_highlight_spans() {
+        // Escaped: ab\u001bcd
+        let unescaped = "ab\u{1b}cd";
+        assert_eq!(vec![1..9], escaped_highlight_spans(unescaped, &[1..2, 2..3, 3..4]));
+        assert_eq!(vec![1..2, 8..9], escaped_highlight_spans(unescaped, &[1..2, 3..4]));
+        assert_eq!(vec![2..8], escaped_highlight_spans(unescaped, &[2..3, 3..3]));
+        assert!(escaped_highlight_spans(unescaped, &[]).is_empty());
+        assert!(escaped_highlight_spans(unescaped, &[9..12, 15..20]).is_empty());
+
+        // Escaped: \ud83d\ude00 \u00e9
+        // Ranges inside a char expand to cover its whole escape.
+        let unescaped = "😀 é";
+        assert_eq!(vec![0..12], escaped_highlight_spans(unescaped, &[1..2, 2..3]));
+        assert_eq!(vec![0..19], escaped_highlight_spans(unescaped, &[0..1, 4..6]));
+        assert_eq!(vec![0..12, 13..19], escaped_highlight_spans(unescaped, &[0..4, 5..6]));
+
+        let mut rng = XorShift(0xA0761D6478BD642F);
+        for _ in 0..500 {
+            let unescaped = rng.string(12);
+            let index = IndexedString::new(&unescaped);
+            let mut ranges: Vec<Range<usize>> = (0..rng.next() % 4)
+                .map(|_| {
+                    let a = rng.next() as usize % (unescaped.len() + 2);
+                    let b = rng.next() as usize % (unescaped.len() + 2);
+                    a.min(b)..a.max(b)
+                })
+                .collect();
+            ranges.sort_by_key(|range| range.start);
+
+            let mut expected: Vec<Range<usize>> = ranges
+                .iter()
+                .filter(|range| !range.is_empty())
+                .map(|range| index.unescaped_to_escaped(range.clone()).1)
+                .filter(|range| !range.is_empty())
+                .collect();
+            merge_ranges(&mut expected);
+            assert_eq!(expected, escaped_highlight_spans(&unescaped, &ranges));
+        }
+    }
+
+    #[test]
+    fn test_split_escaped_strings() {
+        #[track_caller]
+        fn check(s: &str, expected: &[&str]) {
+            assert_eq!(expected, split_escaped_strings(s).collect::<Vec<_>>());
+        }
+
+        check(r#""abc" "" "d\"e\\" , "fé""#, &["abc", "", r#"d\"e\\"#, "fé"]);
+        check(r#""a""b""#, &["a", "b"]);
+        check(r#""say \"hi\"""#, &[r#"say \"hi\""#]);
+        check(r#"no strings"#, &[]);
+        check("", &[]);
+        // The last string is unterminated.
+        check(r#""a" "b\""#, &["a", r#"b\""#]);
+    }
+
+    #[test]
+    fn test_escaped_chunks() {
+        #[track_caller]
+        fn assert_chunks(escaped: &str, expected: &[&str]) {
+            let chunks: Vec<&str> = escaped_chunks(escaped)
+                .map(|range| &escaped[range])
+                .collect();
+            assert_eq!(expected, &chunks[..]);
+        }
+
+        assert_chunks("", &[]);
+        assert_chunks("ab", &["a", "b"]);
+        assert_chunks("a\\nb\\\"", &["a", "\\n", "b", "\\\""]);
+        assert_chunks("x\\u00e9中", &["x", "\\u00e9", "中"]);
+        assert_chunks("\\uD801\\uDC37!", &["\\uD801\\uDC37", "!"]);
+        // Unpaired surrogates are chunked individually.
+        assert_chunks("\\uD801\\u0041", &["\\uD801", "\\u0041"]);
+        assert_chunks("\\uDC37\\uD801", &["\\uDC37", "\\uD801"]);
+        // Malformed input still gets chunked without splitting chars.
+        assert_chunks("\\u12", &["\\u", "1", "2"]);
+        assert_chunks("\\é\\", &["\\é", "\\"]);
+    }
+
+    #[test]
+    fn test_nth_escape_range() {
+        let escaped = "a\\nb\\u00e9c\\uD801\\uDC37\\\\d";
+        assert_eq!(Some(1..3), nth_escape_range(escaped, 0));
+        assert_eq!(Some(4..10), nth_escape_range(escaped, 1));
+        assert_eq!(Some(11..23), nth_escape_range(escaped, 2));
+        assert_eq!(Some(23..25), nth_escape_range(escaped, 3));
+        assert_eq!(None, nth_escape_range(escaped, 4));
+        assert_eq!(None, nth_escape_range("no escapes", 0));
+    }
+
+    #[test]
+    fn test_find_in_escaped() {
+        assert_eq!(Some(1), find_in_escaped("a\\nb", "\\n"));
+        assert_eq!(Some(0), find_in_escaped("a\\nb", "a\\n"));
+        assert_eq!(Some(0), find_in_escaped("abc", ""));
+        assert_eq!(None, find_in_escaped("abc", "abcd"));
+
+        // Matches can't split an escape.
+        assert_eq!(None, find_in_escaped("\\uABCD", "\\uAB"));
+        assert_eq!(None, find_in_escaped("\\uABCD", "ABCD"));
+        assert_eq!(None, find_in_escaped("x\\\\n", "\\n"));
+        assert_eq!(Some(3), find_in_escaped("x\\\\n\\n", "n\\n"));
+        // A surrogate pair is a single unit.
+        assert_eq!(None, find_in_escaped("\\uD83D\\uDE00", "\\uDE00"));
+        assert_eq!(Some(1), find_in_escaped("a\\uD83D\\uDE00", "\\uD83D\\uDE00"));
+
+        // Later matches are still found after rejected ones.
+        assert_eq!(Some(7), find_in_escaped("\\\\u0041\\u0041", "\\u0041"));
+    }
+
+    #[test]
+    fn test_truncate_to_bytes() {
+        let escaped = "ab€\\u00e9\\uD83D\\uDE00z";
+        assert_eq!("", truncate_to_bytes(escaped, 0));
+        assert_eq!("ab", truncate_to_bytes(escaped, 2));
+        // Inside "€", which is 3 bytes
+        assert_eq!("ab", truncate_to_bytes(escaped, 4));
+        assert_eq!("ab€", truncate_to_bytes(escaped, 5));
+        // Inside "\u00e9"
+        assert_eq!("ab€", truncate_to_bytes(escaped, 8));
+        assert_eq!("ab€\\u00e9", truncate_to_bytes(escaped, 11));
+        // Inside the surrogate pair
+        assert_eq!("ab€\\u00e9", truncate_to_bytes(escaped, 17));
+        assert_eq!("ab€\\u00e9\\uD83D\\uDE00", truncate_to_bytes(escaped, 23));
+        assert_eq!(escaped, truncate_to_bytes(escaped, 24));
+        assert_eq!(escaped, truncate_to_bytes(escaped, 100));
     }
 }
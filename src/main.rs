@@ -19,6 +19,7 @@ use termion::raw::IntoRawMode;
 use termion::screen::AlternateScreen;
 
 mod app;
+mod escapedwidth;
 mod flatjson;
 mod highlighting;
 mod input;